@@ -0,0 +1,335 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+/// Table built into the updater
+static BUILTIN_TABLE: &str = include_str!("ec-models.txt");
+
+// SMBIOS memory device types
+const MEMORY_DDR4: u8 = 0x1A;
+const MEMORY_DDR5: u8 = 0x22;
+
+/// Hardware state checked by predicates, so the table can be evaluated without hardware
+pub trait Hardware {
+    /// Device and vendor ID of a PCI function, if it could be read
+    fn pci_id(&self, bus: u8, dev: u8, func: u8) -> Option<u32>;
+    fn gpio_high(&self, port: u8, pad: u8) -> bool;
+    /// SMBIOS memory type of the first memory device
+    fn memory_kind(&self) -> Option<u8>;
+    fn ec_version(&self) -> &str;
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Predicate {
+    Pci { bus: u8, dev: u8, func: u8, id: u32 },
+    Gpio { port: u8, pad: u8, high: bool },
+    Memory(u8),
+    EcVersion(String),
+}
+
+impl Predicate {
+    fn parse(text: &str) -> Option<Self> {
+        let (key, value) = text.split_once('=')?;
+        match key {
+            "pci" => {
+                // BB:DD.F=VVVVDDDD
+                let (location, id) = value.split_once('=')?;
+                let (bus, dev_func) = location.split_once(':')?;
+                let (dev, func) = dev_func.split_once('.')?;
+                Some(Self::Pci {
+                    bus: u8::from_str_radix(bus, 16).ok()?,
+                    dev: u8::from_str_radix(dev, 16).ok()?,
+                    func: u8::from_str_radix(func, 16).ok()?,
+                    id: u32::from_str_radix(id, 16).ok()?,
+                })
+            }
+            "gpio" => {
+                // PP:NN=high|low
+                let (location, state) = value.split_once('=')?;
+                let (port, pad) = location.split_once(':')?;
+                let high = match state {
+                    "high" => true,
+                    "low" => false,
+                    _ => return None,
+                };
+                Some(Self::Gpio {
+                    port: u8::from_str_radix(port, 16).ok()?,
+                    pad: u8::from_str_radix(pad, 16).ok()?,
+                    high,
+                })
+            }
+            "memory" => match value {
+                "ddr4" => Some(Self::Memory(MEMORY_DDR4)),
+                "ddr5" => Some(Self::Memory(MEMORY_DDR5)),
+                _ => None,
+            },
+            "ec-version" => Some(Self::EcVersion(value.to_string())),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, hardware: &dyn Hardware) -> bool {
+        match self {
+            Self::Pci { bus, dev, func, id } => hardware.pci_id(*bus, *dev, *func) == Some(*id),
+            Self::Gpio { port, pad, high } => hardware.gpio_high(*port, *pad) == *high,
+            Self::Memory(kind) => hardware.memory_kind() == Some(*kind),
+            Self::EcVersion(prefix) => hardware.ec_version().starts_with(prefix.as_str()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Alias {
+    pub alias: String,
+    pub model: String,
+    pub predicates: Vec<Predicate>,
+}
+
+/// Board names reported by ODM EC firmware, and the System76 models they map to
+pub struct AliasTable {
+    pub aliases: Vec<Alias>,
+}
+
+impl AliasTable {
+    /// Parse the table built into the updater
+    pub fn builtin() -> Result<Self, String> {
+        Self::parse(BUILTIN_TABLE)
+    }
+
+    /// Parse lines of `ALIAS MODEL [PREDICATE...]`
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut aliases = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+            let (alias, model) = match (parts.next(), parts.next()) {
+                (Some(alias), Some(model)) => (alias, model),
+                _ => return Err(format!("invalid line {}: {:?}", i + 1, line)),
+            };
+            let predicates = parts
+                .map(|part| {
+                    Predicate::parse(part)
+                        .ok_or_else(|| format!("invalid predicate on line {}: {:?}", i + 1, part))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            aliases.push(Alias {
+                alias: alias.to_string(),
+                model: model.to_string(),
+                predicates,
+            });
+        }
+        Ok(Self { aliases })
+    }
+
+    /// Add rows from another table, like one from the firmware bundle. Its rows for a board
+    /// name replace all rows for that name in this table, and the replaced names are returned.
+    pub fn extend(&mut self, other: Self) -> Vec<String> {
+        let mut replaced: Vec<String> = Vec::new();
+        for alias in other.aliases.iter() {
+            if !replaced.contains(&alias.alias)
+                && self
+                    .aliases
+                    .iter()
+                    .any(|existing| existing.alias == alias.alias)
+            {
+                replaced.push(alias.alias.clone());
+            }
+        }
+        self.aliases
+            .retain(|alias| !replaced.contains(&alias.alias));
+        self.aliases.extend(other.aliases);
+        replaced
+    }
+
+    /// Model for a board name, using the first row for it where every predicate holds. Only
+    /// rows for this board name are checked, so hardware of other boards is never probed.
+    pub fn normalize(&self, name: &str, hardware: &dyn Hardware) -> String {
+        self.aliases
+            .iter()
+            .filter(|alias| alias.alias == name)
+            .find(|alias| {
+                alias
+                    .predicates
+                    .iter()
+                    .all(|predicate| predicate.matches(hardware))
+            })
+            .map_or_else(|| name.to_string(), |alias| alias.model.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[derive(Default)]
+    struct FakeHardware {
+        pci: Vec<((u8, u8, u8), u32)>,
+        gpio_high: Vec<(u8, u8)>,
+        memory_kind: Option<u8>,
+        ec_version: String,
+    }
+
+    impl Hardware for FakeHardware {
+        fn pci_id(&self, bus: u8, dev: u8, func: u8) -> Option<u32> {
+            self.pci
+                .iter()
+                .find(|(location, _)| *location == (bus, dev, func))
+                .map(|(_, id)| *id)
+        }
+
+        fn gpio_high(&self, port: u8, pad: u8) -> bool {
+            self.gpio_high.contains(&(port, pad))
+        }
+
+        fn memory_kind(&self) -> Option<u8> {
+            self.memory_kind
+        }
+
+        fn ec_version(&self) -> &str {
+            &self.ec_version
+        }
+    }
+
+    /// Hardware that fails the test if any of it is probed
+    struct NoHardware;
+
+    impl Hardware for NoHardware {
+        fn pci_id(&self, bus: u8, dev: u8, func: u8) -> Option<u32> {
+            panic!("probed PCI {:02x}:{:02x}.{}", bus, dev, func);
+        }
+
+        fn gpio_high(&self, port: u8, pad: u8) -> bool {
+            panic!("probed GPIO {:02x}:{:02x}", port, pad);
+        }
+
+        fn memory_kind(&self) -> Option<u8> {
+            panic!("probed memory type");
+        }
+
+        fn ec_version(&self) -> &str {
+            panic!("probed EC version");
+        }
+    }
+
+    #[test]
+    fn builtin_table_parses() {
+        let table = AliasTable::builtin().unwrap();
+        assert!(!table.aliases.is_empty());
+        assert!(
+            table
+                .aliases
+                .iter()
+                .all(|alias| alias.model.starts_with("system76/"))
+        );
+    }
+
+    #[test]
+    fn plain_aliases_do_not_probe_hardware() {
+        let table = AliasTable::builtin().unwrap();
+        assert_eq!(table.normalize("L140CU", &NoHardware), "system76/lemp9");
+        assert_eq!(table.normalize("NV40Mx-DV", &NoHardware), "system76/galp5");
+        assert_eq!(table.normalize("X58xWNx", &NoHardware), "system76/bonw16");
+    }
+
+    #[test]
+    fn unknown_and_system76_names_are_kept() {
+        let table = AliasTable::builtin().unwrap();
+        assert_eq!(
+            table.normalize("system76/darp9", &NoHardware),
+            "system76/darp9"
+        );
+        assert_eq!(table.normalize("UNKNOWN", &NoHardware), "UNKNOWN");
+    }
+
+    #[test]
+    fn ec_version_prefix() {
+        let table = AliasTable::builtin().unwrap();
+        let mut hardware = FakeHardware {
+            ec_version: "1.07.02".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(table.normalize("L2x0TU", &hardware), "system76/lemp13");
+        hardware.ec_version = "1.08.00".to_string();
+        assert_eq!(table.normalize("L2x0TU", &hardware), "system76/lemp13-b");
+    }
+
+    #[test]
+    fn pci_device() {
+        let table = AliasTable::builtin().unwrap();
+        let mut hardware = FakeHardware::default();
+        assert_eq!(
+            table.normalize("NPxxPNP", &hardware),
+            "system76/gaze17-3060"
+        );
+        hardware.pci = vec![((0x00, 0x1f, 0x6), 0x1a1f8086)];
+        assert_eq!(
+            table.normalize("NPxxPNP", &hardware),
+            "system76/gaze17-3060-b"
+        );
+    }
+
+    #[test]
+    fn pci_device_and_gpio() {
+        let table = AliasTable::builtin().unwrap();
+        let mut hardware = FakeHardware {
+            pci: vec![((0x00, 0x1f, 0x5), 0x7e238086)],
+            ..Default::default()
+        };
+        assert_eq!(table.normalize("V5x0TU", &hardware), "system76/darp10");
+        hardware.gpio_high = vec![(0xd2, 0x36)];
+        assert_eq!(table.normalize("V5x0TU", &hardware), "system76/darp10-b");
+        hardware.pci = vec![((0x00, 0x1f, 0x5), 0x77238086)];
+        assert_eq!(table.normalize("V5x0TU", &hardware), "system76/darp11-b");
+        hardware.pci.clear();
+        assert_eq!(table.normalize("V5x0TU", &hardware), "V5x0TU");
+    }
+
+    #[test]
+    fn memory_type() {
+        let table = AliasTable::builtin().unwrap();
+        let mut hardware = FakeHardware::default();
+        assert_eq!(table.normalize("PDxxPNx", &hardware), "PDxxPNx");
+        hardware.memory_kind = Some(MEMORY_DDR4);
+        assert_eq!(table.normalize("PDxxPNx", &hardware), "system76/oryp9");
+        hardware.memory_kind = Some(MEMORY_DDR5);
+        assert_eq!(table.normalize("PDxxPNx", &hardware), "system76/oryp10");
+    }
+
+    #[test]
+    fn extend_replaces_builtin_names() {
+        let mut table = AliasTable::builtin().unwrap();
+        let extra = AliasTable::parse(
+            "L140CU system76/oryp9 memory=ddr5\n\
+             NEW1 system76/new1 memory=ddr5\n\
+             NEW1 system76/new1-b\n\
+             L140CU system76/oryp10",
+        )
+        .unwrap();
+        assert_eq!(table.extend(extra), vec!["L140CU".to_string()]);
+
+        // Every built in row for a replaced name is gone, in favor of the bundle rows in order
+        let mut hardware = FakeHardware::default();
+        assert_eq!(table.normalize("L140CU", &hardware), "system76/oryp10");
+        hardware.memory_kind = Some(MEMORY_DDR5);
+        assert_eq!(table.normalize("L140CU", &hardware), "system76/oryp9");
+        assert_eq!(table.normalize("NEW1", &hardware), "system76/new1");
+        assert_eq!(table.normalize("L140MU", &NoHardware), "system76/lemp10");
+    }
+
+    #[test]
+    fn invalid_lines_are_rejected() {
+        assert!(AliasTable::parse("L140CU").is_err());
+        assert!(AliasTable::parse("L140CU system76/lemp9 pci=00:1f").is_err());
+        assert!(AliasTable::parse("L140CU system76/lemp9 gpio=d2:36=on").is_err());
+        assert!(AliasTable::parse("L140CU system76/lemp9 memory=ddr3").is_err());
+        assert!(AliasTable::parse("L140CU system76/lemp9 # comment").is_ok());
+    }
+}
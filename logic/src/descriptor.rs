@@ -0,0 +1,420 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::sector::RomReader;
+
+// intelflash validates the descriptor, but does not expose the master section, so the regions
+// and access permissions are parsed here
+const FLVALSIG: u32 = 0x0FF0_A55A;
+
+pub static REGION_NAMES: [&str; 9] = [
+    "Descriptor",
+    "BIOS",
+    "ME",
+    "Ethernet",
+    "Platform Data",
+    "Device Expansion",
+    "BIOS 2",
+    "Reserved",
+    "EC",
+];
+
+/// Regions that hold data specific to each unit, which are carried over from the old firmware
+static PRESERVED_REGIONS: [usize; 2] = [3, 4];
+
+fn read_u32(data: &[u8], offset: usize) -> core::result::Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or_else(|| format!("descriptor offset {:#X} is out of bounds", offset))
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Region {
+    pub index: usize,
+    pub name: &'static str,
+    pub base: usize,
+    pub limit: usize,
+}
+
+impl Region {
+    pub fn range(&self) -> core::ops::Range<usize> {
+        self.base..self.limit + 1
+    }
+}
+
+pub struct Descriptor {
+    /// Version 2 descriptor, used by Skylake and later
+    pub v2: bool,
+    pub regions: Vec<Region>,
+    /// Host CPU/BIOS master (FLMSTR1) read access bits, one per region
+    pub host_read: u32,
+    /// Host CPU/BIOS master (FLMSTR1) write access bits, one per region
+    pub host_write: u32,
+}
+
+impl Descriptor {
+    /// Parse the flash descriptor from the first sector of a chip of `len` bytes
+    pub fn from_head(data: &[u8], len: usize) -> core::result::Result<Option<Self>, String> {
+        if read_u32(data, 0x10)? != FLVALSIG {
+            return Ok(None);
+        }
+
+        let flmap0 = read_u32(data, 0x14)?;
+        let flmap1 = read_u32(data, 0x18)?;
+        let fcba = ((flmap0 & 0xFF) as usize) << 4;
+        let frba = (((flmap0 >> 16) & 0xFF) as usize) << 4;
+        let fmba = ((flmap1 & 0xFF) as usize) << 4;
+
+        // Version 2 descriptors (Skylake and later) are detected by the read clock frequency,
+        // the same way as ifdtool
+        let flcomp = read_u32(data, fcba)?;
+        let v2 = (flcomp >> 17) & 0x7 != 0;
+        let (count, read_shift, write_shift) = if v2 { (9, 8, 20) } else { (5, 16, 24) };
+
+        let mut regions = Vec::new();
+        for (index, name) in REGION_NAMES.iter().copied().enumerate().take(count) {
+            let flreg = read_u32(data, frba + index * 4)?;
+            let base = ((flreg & 0x7FFF) as usize) << 12;
+            let limit = ((((flreg >> 16) & 0x7FFF) as usize) << 12) | 0xFFF;
+            // Unused regions have a base above the limit
+            if base > limit {
+                continue;
+            }
+            if limit >= len {
+                return Err(format!(
+                    "{} region {:#X}:{:#X} is larger than the image",
+                    name, base, limit
+                ));
+            }
+            regions.push(Region {
+                index,
+                name,
+                base,
+                limit,
+            });
+        }
+
+        let flmstr1 = read_u32(data, fmba)?;
+        let mask = (1 << count) - 1;
+        Ok(Some(Self {
+            v2,
+            regions,
+            host_read: (flmstr1 >> read_shift) & mask,
+            host_write: (flmstr1 >> write_shift) & mask,
+        }))
+    }
+
+    pub fn region(&self, index: usize) -> Option<&Region> {
+        self.regions.iter().find(|region| region.index == index)
+    }
+
+    pub fn host_writable(&self, index: usize) -> bool {
+        self.host_write & (1 << index) != 0
+    }
+
+    /// Describe the regions and host access to them, one line each
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        for region in self.regions.iter() {
+            let _ = writeln!(
+                report,
+                "IFD: {} {:#08X}:{:#08X}: host {}{}",
+                region.name,
+                region.base,
+                region.limit,
+                if self.host_read & (1 << region.index) != 0 {
+                    "read"
+                } else {
+                    "no read"
+                },
+                if self.host_writable(region.index) {
+                    ", write"
+                } else {
+                    ", no write"
+                }
+            );
+        }
+        report
+    }
+}
+
+/// Build the new image region by region: regions the host cannot write and regions specific to
+/// this unit are taken from the old image. Fails if the region layout changes in a way that
+/// cannot be written. Returns what was done, one line each.
+pub fn apply(
+    old: &Descriptor,
+    old_data: &mut dyn RomReader,
+    new: &Descriptor,
+    new_data: &mut [u8],
+) -> core::result::Result<String, String> {
+    let mut report = String::new();
+
+    let mut layout_changed = false;
+    for (index, name) in REGION_NAMES.iter().copied().enumerate() {
+        let old_region = old.region(index);
+        let new_region = new.region(index);
+        if old_region.map(Region::range) == new_region.map(Region::range) {
+            continue;
+        }
+
+        layout_changed = true;
+        if !old.host_writable(index) && old_region.is_some() {
+            return Err(format!(
+                "{} region changes from {:X?} to {:X?}, but is not writable by the host",
+                name,
+                old_region.map(Region::range),
+                new_region.map(Region::range)
+            ));
+        }
+        if PRESERVED_REGIONS.contains(&index) {
+            let old_size = old_region.map(|region| region.range().len());
+            let new_size = new_region.map(|region| region.range().len());
+            if old_size != new_size {
+                return Err(format!(
+                    "{} region size changes from {:X?} to {:X?}, so it cannot be preserved",
+                    name, old_size, new_size
+                ));
+            }
+        }
+    }
+
+    if layout_changed {
+        if !old.host_writable(0) {
+            return Err("region layout changes, but the descriptor is not writable".to_string());
+        }
+        let _ = writeln!(
+            report,
+            "IFD: region layout changes, all changed regions are writable"
+        );
+    }
+
+    for old_region in old.regions.iter() {
+        let index = old_region.index;
+        let preserve = PRESERVED_REGIONS.contains(&index);
+        if old.host_writable(index) && !preserve {
+            continue;
+        }
+
+        let new_region = match new.region(index) {
+            Some(new_region) => new_region,
+            None => continue,
+        };
+        let old_range = old_region.range();
+        let new_range = new_region.range();
+        if old_range.len() != new_range.len() {
+            // Only possible for writable regions that are not preserved, checked above
+            continue;
+        }
+
+        old_data.read_at(old_range.start, &mut new_data[new_range])?;
+        if !old.host_writable(index) {
+            let _ = writeln!(
+                report,
+                "{}: not writable by the host, keeping region from old firmware",
+                old_region.name
+            );
+        } else {
+            let _ = writeln!(
+                report,
+                "{}: copied region from old firmware to new firmware",
+                old_region.name
+            );
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const LEN: usize = 0x10_0000;
+    const FCBA: usize = 0x30;
+    const FRBA: usize = 0x40;
+    const FMBA: usize = 0x80;
+
+    const DESCRIPTOR: usize = 0;
+    const BIOS: usize = 1;
+    const ME: usize = 2;
+    const GBE: usize = 3;
+    const PDR: usize = 4;
+    const EC: usize = 8;
+
+    /// Image of `LEN` bytes filled with `fill`, with a descriptor for the regions, given as
+    /// index, base, and limit, and the FLMSTR1 read and write bits
+    fn image(
+        v2: bool,
+        regions: &[(usize, usize, usize)],
+        read: u32,
+        write: u32,
+        fill: u8,
+    ) -> Vec<u8> {
+        let mut data = vec![fill; LEN];
+        let mut put = |offset: usize, value: u32| {
+            data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        put(0x10, FLVALSIG);
+        put(0x14, ((FRBA as u32 >> 4) << 16) | (FCBA as u32 >> 4));
+        put(0x18, FMBA as u32 >> 4);
+        // 50 MHz read clock for version 2, 20 MHz for version 1
+        put(FCBA, if v2 { 0b110 << 17 } else { 0 });
+        let count = if v2 { 9 } else { 5 };
+        for index in 0..count {
+            // Unused regions have a base above the limit
+            put(FRBA + index * 4, 0x7FFF);
+        }
+        for &(index, base, limit) in regions {
+            put(
+                FRBA + index * 4,
+                ((limit as u32 >> 12) << 16) | (base as u32 >> 12),
+            );
+        }
+        let (read_shift, write_shift) = if v2 { (8, 20) } else { (16, 24) };
+        put(FMBA, (read << read_shift) | (write << write_shift));
+        data
+    }
+
+    fn bits(indexes: &[usize]) -> u32 {
+        indexes.iter().map(|index| 1 << index).sum()
+    }
+
+    const LAYOUT: &[(usize, usize, usize)] = &[
+        (DESCRIPTOR, 0x0000, 0x0FFF),
+        (GBE, 0x1000, 0x2FFF),
+        (PDR, 0x3000, 0x3FFF),
+        (ME, 0x4000, 0x7FFF),
+        (BIOS, 0x8000, LEN - 1),
+    ];
+
+    fn parse(data: &[u8]) -> Descriptor {
+        Descriptor::from_head(data, data.len()).unwrap().unwrap()
+    }
+
+    #[test]
+    fn no_descriptor() {
+        assert!(Descriptor::from_head(&[0; 4096], LEN).unwrap().is_none());
+    }
+
+    #[test]
+    fn version_1() {
+        let all = bits(&[DESCRIPTOR, BIOS, ME, GBE, PDR]);
+        let data = image(false, LAYOUT, all, bits(&[BIOS, GBE]), 0);
+        let descriptor = parse(&data);
+        assert!(!descriptor.v2);
+        assert_eq!(descriptor.regions.len(), 5);
+        assert_eq!(
+            descriptor.region(ME).map(Region::range),
+            Some(0x4000..0x8000)
+        );
+        assert_eq!(descriptor.host_read, all);
+        assert!(descriptor.host_writable(BIOS));
+        assert!(descriptor.host_writable(GBE));
+        assert!(!descriptor.host_writable(ME));
+        assert!(!descriptor.host_writable(DESCRIPTOR));
+    }
+
+    #[test]
+    fn version_2() {
+        let mut layout = LAYOUT.to_vec();
+        layout.push((EC, 0x8000, 0xFFFF));
+        layout[4] = (BIOS, 0x1_0000, LEN - 1);
+        let data = image(true, &layout, bits(&[BIOS, EC]), bits(&[BIOS, EC]), 0);
+        let descriptor = parse(&data);
+        assert!(descriptor.v2);
+        assert_eq!(descriptor.regions.len(), 6);
+        assert_eq!(descriptor.region(EC).map(|region| region.name), Some("EC"));
+        assert!(descriptor.host_writable(EC));
+        assert!(!descriptor.host_writable(ME));
+        assert_eq!(descriptor.host_read, bits(&[BIOS, EC]));
+    }
+
+    #[test]
+    fn version_1_ignores_v2_regions() {
+        let mut layout = LAYOUT.to_vec();
+        layout.push((EC, 0x8000, 0xFFFF));
+        let data = image(false, &layout, 0, 0, 0);
+        assert!(parse(&data).region(EC).is_none());
+    }
+
+    #[test]
+    fn region_outside_image() {
+        let data = image(false, LAYOUT, 0, 0, 0);
+        assert!(Descriptor::from_head(&data, LEN / 2).is_err());
+    }
+
+    #[test]
+    fn keeps_unit_and_locked_regions() {
+        let write = bits(&[DESCRIPTOR, BIOS, GBE, PDR]);
+        let mut old = image(false, LAYOUT, write, write, 0xAA);
+        let mut new = image(false, LAYOUT, write, write, 0x55);
+        let old_descriptor = parse(&old);
+        let new_descriptor = parse(&new);
+
+        let report = apply(&old_descriptor, &mut old, &new_descriptor, &mut new).unwrap();
+        // GbE and PDR are specific to the unit, and ME is not writable
+        assert!(new[0x1000..0x8000].iter().all(|&byte| byte == 0xAA));
+        assert!(new[0x8000..].iter().all(|&byte| byte == 0x55));
+        assert!(report.contains("Ethernet: copied"));
+        assert!(report.contains("Platform Data: copied"));
+        assert!(report.contains("ME: not writable"));
+        assert!(!report.contains("layout changes"));
+    }
+
+    #[test]
+    fn layout_changed() {
+        let write = bits(&[DESCRIPTOR, BIOS, ME, GBE, PDR]);
+        let mut old = image(true, LAYOUT, write, write, 0xAA);
+        let mut layout = LAYOUT.to_vec();
+        layout[3] = (ME, 0x4000, 0xFFFF);
+        layout[4] = (BIOS, 0x1_0000, LEN - 1);
+        let mut new = image(true, &layout, write, write, 0x55);
+        let old_descriptor = parse(&old);
+        let new_descriptor = parse(&new);
+
+        let report = apply(&old_descriptor, &mut old, &new_descriptor, &mut new).unwrap();
+        assert!(report.contains("layout changes"));
+        assert!(new[0x1000..0x4000].iter().all(|&byte| byte == 0xAA));
+        assert!(new[0x4000..].iter().all(|&byte| byte == 0x55));
+    }
+
+    #[test]
+    fn layout_changed_locked() {
+        let mut layout = LAYOUT.to_vec();
+        layout[3] = (ME, 0x4000, 0xFFFF);
+        layout[4] = (BIOS, 0x1_0000, LEN - 1);
+
+        // Locked ME region cannot move
+        let write = bits(&[DESCRIPTOR, BIOS, GBE, PDR]);
+        let mut old = image(true, LAYOUT, write, write, 0);
+        let mut new = image(true, &layout, write, write, 0);
+        let (old_descriptor, new_descriptor) = (parse(&old), parse(&new));
+        let err = apply(&old_descriptor, &mut old, &new_descriptor, &mut new).unwrap_err();
+        assert!(err.starts_with("ME region changes"));
+
+        // Regions cannot move without writing the descriptor
+        let write = bits(&[BIOS, ME, GBE, PDR]);
+        let mut old = image(true, LAYOUT, write, write, 0);
+        let mut new = image(true, &layout, write, write, 0);
+        let (old_descriptor, new_descriptor) = (parse(&old), parse(&new));
+        let err = apply(&old_descriptor, &mut old, &new_descriptor, &mut new).unwrap_err();
+        assert!(err.contains("descriptor is not writable"));
+    }
+
+    #[test]
+    fn preserved_region_resized() {
+        let write = bits(&[DESCRIPTOR, BIOS, ME, GBE, PDR]);
+        let mut layout = LAYOUT.to_vec();
+        layout[1] = (GBE, 0x1000, 0x1FFF);
+        let mut old = image(false, LAYOUT, write, write, 0);
+        let mut new = image(false, &layout, write, write, 0);
+        let (old_descriptor, new_descriptor) = (parse(&old), parse(&new));
+        let err = apply(&old_descriptor, &mut old, &new_descriptor, &mut new).unwrap_err();
+        assert!(err.starts_with("Ethernet region size changes"));
+    }
+}
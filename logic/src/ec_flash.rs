@@ -0,0 +1,107 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use alloc::format;
+use alloc::string::String;
+
+/// Capacity of a SPI flash from its JEDEC ID, where the last byte is log2 of the size in bytes
+pub fn jedec_size(id: [u8; 3]) -> Option<usize> {
+    match id[2] {
+        0x10..=0x1F => Some(1 << id[2]),
+        _ => None,
+    }
+}
+
+pub fn jedec_manufacturer(id: u8) -> &'static str {
+    match id {
+        0x1F => "Adesto",
+        0x20 => "Micron",
+        0x85 => "Puya",
+        0x9D => "ISSI",
+        0xBF => "SST",
+        0xC2 => "Macronix",
+        0xC8 => "GigaDevice",
+        0xEF => "Winbond",
+        _ => "unknown manufacturer",
+    }
+}
+
+/// Check the size of an EC image against the size of its flash, if it is known. Images must be a
+/// whole number of KB, and fit in the flash. If `fill` is set, they must fill it exactly, and
+/// otherwise the rest of the flash is left as it is.
+pub fn check_image_size(image: usize, flash: Option<usize>, fill: bool) -> Result<(), String> {
+    if image == 0 || image % 1024 != 0 {
+        return Err(format!("ROM size of {} is not valid", image));
+    }
+    match flash {
+        Some(flash) if image > flash => Err(format!(
+            "ROM size of {} is larger than flash size of {}",
+            image, flash
+        )),
+        Some(flash) if fill && image != flash => Err(format!(
+            "ROM size of {} does not match flash size of {}",
+            image, flash
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jedec_sizes() {
+        // Winbond W25Q80, W25Q16, and W25X40
+        assert_eq!(jedec_size([0xEF, 0x40, 0x14]), Some(1024 * 1024));
+        assert_eq!(jedec_size([0xEF, 0x40, 0x15]), Some(2 * 1024 * 1024));
+        assert_eq!(jedec_size([0xEF, 0x30, 0x13]), Some(512 * 1024));
+        // GigaDevice GD25Q10 and the smallest and largest decoded sizes
+        assert_eq!(jedec_size([0xC8, 0x40, 0x11]), Some(128 * 1024));
+        assert_eq!(jedec_size([0x00, 0x00, 0x10]), Some(64 * 1024));
+        assert_eq!(jedec_size([0x00, 0x00, 0x1F]), Some(1 << 31));
+    }
+
+    #[test]
+    fn jedec_unknown() {
+        // No chip answering reads as all ones or all zeros
+        assert_eq!(jedec_size([0xFF, 0xFF, 0xFF]), None);
+        assert_eq!(jedec_size([0x00, 0x00, 0x00]), None);
+        assert_eq!(jedec_size([0xEF, 0x40, 0x0F]), None);
+        assert_eq!(jedec_size([0xEF, 0x40, 0x20]), None);
+    }
+
+    #[test]
+    fn jedec_manufacturers() {
+        assert_eq!(jedec_manufacturer(0xEF), "Winbond");
+        assert_eq!(jedec_manufacturer(0xC8), "GigaDevice");
+        assert_eq!(jedec_manufacturer(0xFF), "unknown manufacturer");
+    }
+
+    #[test]
+    fn image_fits_flash() {
+        assert_eq!(
+            check_image_size(128 * 1024, Some(128 * 1024), false),
+            Ok(())
+        );
+        assert_eq!(
+            check_image_size(128 * 1024, Some(256 * 1024), false),
+            Ok(())
+        );
+        assert!(check_image_size(256 * 1024, Some(128 * 1024), false).is_err());
+    }
+
+    #[test]
+    fn image_fills_flash() {
+        assert_eq!(check_image_size(128 * 1024, Some(128 * 1024), true), Ok(()));
+        assert!(check_image_size(128 * 1024, Some(256 * 1024), true).is_err());
+        assert!(check_image_size(256 * 1024, Some(128 * 1024), true).is_err());
+    }
+
+    #[test]
+    fn image_unknown_flash() {
+        assert_eq!(check_image_size(128 * 1024, None, true), Ok(()));
+        assert_eq!(check_image_size(128 * 1024, None, false), Ok(()));
+        assert!(check_image_size(128 * 1024 + 1, None, false).is_err());
+        assert!(check_image_size(0, None, false).is_err());
+    }
+}
@@ -0,0 +1,761 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::sector::{ERASE_BYTE, Plan, RomReader};
+
+/// Size of reads from the old firmware when comparing moved areas
+pub const DIFF_CHUNK: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Area {
+    pub offset: usize,
+    pub size: usize,
+}
+
+impl Area {
+    pub fn range(&self) -> core::ops::Range<usize> {
+        self.offset..self.offset + self.size
+    }
+}
+
+pub struct Fmap {
+    pub name: String,
+    pub areas: BTreeMap<String, Area>,
+}
+
+impl Fmap {
+    /// Describe the FMAP and its areas, one line each
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        let _ = writeln!(report, "  {}", self.name);
+        for (name, area) in self.areas.iter() {
+            let _ = writeln!(
+                report,
+                "    {}: offset {:#X}, size {} KB",
+                name,
+                area.offset,
+                area.size / 1024
+            );
+        }
+        report
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AreaAction {
+    /// Copy the area from the old firmware into the new firmware
+    Preserve,
+    /// Fill the area with erased bytes
+    Clear,
+    /// Use the area from the new firmware
+    New,
+}
+
+impl AreaAction {
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "preserve" => Some(Self::Preserve),
+            "clear" => Some(Self::Clear),
+            "new" => Some(Self::New),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AreaResult {
+    /// Area copied from old firmware at the same offset
+    Copied,
+    /// Area copied from old firmware, but the offset changed
+    Moved {
+        old_offset: usize,
+        new_offset: usize,
+    },
+    /// Area size changed, the new firmware contents are used
+    Resized { old_size: usize, new_size: usize },
+    /// Area filled with erased bytes
+    Cleared,
+    /// Area taken from new firmware as requested
+    New,
+    /// Area is only in the old firmware
+    NotInNew,
+    /// Area is only in the new firmware, so there is nothing to preserve
+    NotInOld,
+    /// Area is in neither firmware
+    Missing,
+}
+
+pub struct AreaPolicy {
+    areas: Vec<(String, AreaAction)>,
+}
+
+impl Default for AreaPolicy {
+    fn default() -> Self {
+        Self {
+            areas: vec![
+                ("SMMSTORE".to_string(), AreaAction::Preserve),
+                ("RO_VPD".to_string(), AreaAction::Preserve),
+                ("RW_VPD".to_string(), AreaAction::Preserve),
+                ("RW_NVRAM".to_string(), AreaAction::Preserve),
+            ],
+        }
+    }
+}
+
+impl AreaPolicy {
+    /// Parse lines of `AREA_NAME action`, where action is preserve, clear, or new
+    pub fn parse(&mut self, text: &str) -> core::result::Result<(), String> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+            let name = parts.next().unwrap_or("");
+            let action = match parts.next().and_then(AreaAction::from_str) {
+                Some(action) if parts.next().is_none() => action,
+                _ => return Err(format!("invalid line {}: {:?}", i + 1, line)),
+            };
+
+            self.set(name, action);
+        }
+        Ok(())
+    }
+
+    pub fn set(&mut self, name: &str, action: AreaAction) {
+        if let Some(entry) = self.areas.iter_mut().find(|(area, _)| area == name) {
+            entry.1 = action;
+        } else {
+            self.areas.push((name.to_string(), action));
+        }
+    }
+
+    /// Apply the policy to the new firmware, reading preserved areas from the old firmware, and
+    /// return what was done with each area for policy_report. Areas are handled in the order
+    /// they were added, so where areas overlap, the last one wins.
+    pub fn apply(
+        &self,
+        old: &Fmap,
+        old_data: &mut dyn RomReader,
+        new: &Fmap,
+        new_data: &mut [u8],
+    ) -> core::result::Result<Vec<(String, AreaResult)>, String> {
+        let mut results = Vec::with_capacity(self.areas.len());
+        for (name, action) in &self.areas {
+            let old_area = old.areas.get(name);
+            let new_area = new.areas.get(name);
+
+            let result = match (action, old_area, new_area) {
+                (_, None, None) => AreaResult::Missing,
+                (_, Some(_), None) => AreaResult::NotInNew,
+                (AreaAction::New, _, Some(_)) => AreaResult::New,
+                (AreaAction::Clear, _, Some(new_area)) => {
+                    new_data
+                        .get_mut(new_area.range())
+                        .ok_or_else(|| format!("{} is larger than the image", name))?
+                        .fill(ERASE_BYTE);
+                    AreaResult::Cleared
+                }
+                (AreaAction::Preserve, None, Some(_)) => AreaResult::NotInOld,
+                (AreaAction::Preserve, Some(old_area), Some(new_area)) => {
+                    if old_area.size != new_area.size {
+                        AreaResult::Resized {
+                            old_size: old_area.size,
+                            new_size: new_area.size,
+                        }
+                    } else {
+                        let slice = new_data
+                            .get_mut(new_area.range())
+                            .ok_or_else(|| format!("{} is larger than the image", name))?;
+                        old_data.read_at(old_area.offset, slice).map_err(|err| {
+                            format!("{}: failed to read old firmware: {}", name, err)
+                        })?;
+
+                        if old_area.offset == new_area.offset {
+                            AreaResult::Copied
+                        } else {
+                            AreaResult::Moved {
+                                old_offset: old_area.offset,
+                                new_offset: new_area.offset,
+                            }
+                        }
+                    }
+                }
+            };
+
+            results.push((name.clone(), result));
+        }
+        Ok(results)
+    }
+}
+
+/// Format the result of AreaPolicy::apply as a human readable report
+pub fn policy_report(results: &[(String, AreaResult)]) -> String {
+    let mut report = String::new();
+    let _ = writeln!(report, "FMAP policy:");
+    for (name, result) in results {
+        let _ = match result {
+            AreaResult::Copied => writeln!(report, "  {}: copied from old firmware", name),
+            AreaResult::Moved {
+                old_offset,
+                new_offset,
+            } => writeln!(
+                report,
+                "  {}: copied from old firmware offset {:#X} to new firmware offset {:#X}",
+                name, old_offset, new_offset
+            ),
+            AreaResult::Resized { old_size, new_size } => writeln!(
+                report,
+                "  {}: WARNING: size changed from {} KB to {} KB, contents will NOT be preserved",
+                name,
+                old_size / 1024,
+                new_size / 1024
+            ),
+            AreaResult::Cleared => writeln!(report, "  {}: cleared", name),
+            AreaResult::New => writeln!(report, "  {}: using new firmware", name),
+            AreaResult::NotInNew => writeln!(
+                report,
+                "  {}: found in old firmware, but not found in new firmware",
+                name
+            ),
+            AreaResult::NotInOld => writeln!(
+                report,
+                "  {}: found in new firmware, but not found in old firmware",
+                name
+            ),
+            AreaResult::Missing => Ok(()),
+        };
+    }
+    report
+}
+
+/// FMAP areas a partial update may write. Every other byte of the new firmware is replaced
+/// with the old firmware, so it stays identical on the chip.
+pub struct Allowlist {
+    pub names: Vec<String>,
+}
+
+impl Allowlist {
+    /// Parse lines of `AREA_NAME`
+    pub fn parse(text: &str) -> Self {
+        let names = text
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .filter(|line| !line.is_empty())
+            .map(|line| line.to_string())
+            .collect();
+        Self { names }
+    }
+
+    /// Check that the FMAP policy did not clear an area outside the allowlist, which the
+    /// partial update would silently put back
+    pub fn check_policy(
+        &self,
+        results: &[(String, AreaResult)],
+    ) -> core::result::Result<(), String> {
+        for (name, result) in results {
+            if *result == AreaResult::Cleared && !self.names.contains(name) {
+                return Err(format!(
+                    "{} is cleared by the FMAP policy, but is not allowed by partial update",
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy everything outside the allowed areas from the old firmware to the new firmware,
+    /// returning the ranges that may be written. Allowed areas must be at the same place in
+    /// both, and aligned to sectors so that no byte outside them is erased. Allowed areas may
+    /// overlap.
+    pub fn apply(
+        &self,
+        old: &Fmap,
+        old_data: &mut dyn RomReader,
+        new: &Fmap,
+        new_data: &mut [u8],
+        sector_size: usize,
+    ) -> core::result::Result<Vec<core::ops::Range<usize>>, String> {
+        let mut ranges = Vec::with_capacity(self.names.len());
+        for name in self.names.iter() {
+            let area = match (old.areas.get(name), new.areas.get(name)) {
+                (Some(old_area), Some(new_area)) if old_area == new_area => *new_area,
+                (Some(old_area), Some(new_area)) => {
+                    return Err(format!(
+                        "{} moves from {:#X}:{:#X} to {:#X}:{:#X}",
+                        name, old_area.offset, old_area.size, new_area.offset, new_area.size
+                    ));
+                }
+                _ => return Err(format!("{} is not in both old and new firmware", name)),
+            };
+            if area.offset % sector_size != 0 || area.size % sector_size != 0 {
+                return Err(format!(
+                    "{} is not aligned to {} KB sectors",
+                    name,
+                    sector_size / 1024
+                ));
+            }
+            if area.offset + area.size > new_data.len() {
+                return Err(format!("{} is larger than the image", name));
+            }
+            ranges.push(area.range());
+        }
+        ranges.sort_by_key(|range| range.start);
+
+        let end = new_data.len()..new_data.len();
+        let mut address = 0;
+        for range in ranges.iter().chain(core::iter::once(&end)) {
+            if address < range.start {
+                for start in (address..range.start).step_by(DIFF_CHUNK) {
+                    let end = (start + DIFF_CHUNK).min(range.start);
+                    old_data.read_at(start, &mut new_data[start..end])?;
+                }
+            }
+            address = address.max(range.end);
+        }
+
+        Ok(ranges)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AreaDiff {
+    Unchanged,
+    Modified {
+        bytes: usize,
+        sectors: usize,
+    },
+    Added(Area),
+    Removed(Area),
+    /// Offset or size changed, with the differing bytes counted if the size is the same
+    Moved {
+        old: Area,
+        new: Area,
+        bytes: usize,
+    },
+}
+
+/// Count differing bytes in an area that did not move. Only sectors in the plan can differ, so
+/// only those are read from the old firmware.
+fn count_changed(
+    old_data: &mut dyn RomReader,
+    area: &Area,
+    new_data: &[u8],
+    plan: &Plan,
+) -> core::result::Result<(usize, usize), String> {
+    let mut bytes = 0;
+    let mut sectors = 0;
+    let mut old = vec![0; plan.sector_size];
+    for sector in plan.sectors.iter() {
+        let start = sector.address.max(area.offset);
+        let end = (sector.address + plan.sector_size).min(area.offset + area.size);
+        if start >= end {
+            continue;
+        }
+
+        let old = &mut old[..end - start];
+        old_data.read_at(start, old)?;
+        let count = old
+            .iter()
+            .zip(new_data.get(start..end).unwrap_or(&[]).iter())
+            .filter(|(a, b)| a != b)
+            .count();
+        if count > 0 {
+            bytes += count;
+            sectors += 1;
+        }
+    }
+    Ok((bytes, sectors))
+}
+
+/// Count differing bytes between an old and new area of the same size at different offsets,
+/// reading the old firmware in chunks
+fn count_moved(
+    old_data: &mut dyn RomReader,
+    old_offset: usize,
+    new: &[u8],
+) -> core::result::Result<usize, String> {
+    let mut bytes = 0;
+    let mut old = vec![0; DIFF_CHUNK];
+    for (i, new_chunk) in new.chunks(DIFF_CHUNK).enumerate() {
+        let old = &mut old[..new_chunk.len()];
+        old_data.read_at(old_offset + i * DIFF_CHUNK, old)?;
+        bytes += old
+            .iter()
+            .zip(new_chunk.iter())
+            .filter(|(a, b)| a != b)
+            .count();
+    }
+    Ok(bytes)
+}
+
+/// Compare each FMAP area of the old and new firmware
+pub fn diff(
+    old: &Fmap,
+    old_data: &mut dyn RomReader,
+    new: &Fmap,
+    new_data: &[u8],
+    plan: &Plan,
+) -> core::result::Result<Vec<(String, AreaDiff)>, String> {
+    let mut diffs = Vec::new();
+    for (name, new_area) in new.areas.iter() {
+        let diff = match old.areas.get(name) {
+            Some(old_area) => {
+                if old_area == new_area {
+                    let (bytes, sectors) = count_changed(old_data, new_area, new_data, plan)?;
+                    if bytes == 0 {
+                        AreaDiff::Unchanged
+                    } else {
+                        AreaDiff::Modified { bytes, sectors }
+                    }
+                } else {
+                    let bytes = if old_area.size == new_area.size {
+                        let new_slice = new_data.get(new_area.range()).unwrap_or(&[]);
+                        count_moved(old_data, old_area.offset, new_slice)?
+                    } else {
+                        new_area.size
+                    };
+                    AreaDiff::Moved {
+                        old: *old_area,
+                        new: *new_area,
+                        bytes,
+                    }
+                }
+            }
+            None => AreaDiff::Added(*new_area),
+        };
+        diffs.push((name.clone(), diff));
+    }
+    for (name, old_area) in old.areas.iter() {
+        if !new.areas.contains_key(name) {
+            diffs.push((name.clone(), AreaDiff::Removed(*old_area)));
+        }
+    }
+    Ok(diffs)
+}
+
+/// Format the result of diff as a human readable report
+pub fn report(old: &Fmap, new: &Fmap, diffs: &[(String, AreaDiff)]) -> String {
+    let mut report = String::new();
+    let _ = writeln!(report, "FMAP diff: {} -> {}", old.name, new.name);
+    for (name, diff) in diffs {
+        let _ = match diff {
+            AreaDiff::Unchanged => writeln!(report, "  {}: unchanged", name),
+            AreaDiff::Modified { bytes, sectors } => writeln!(
+                report,
+                "  {}: modified, {} bytes differ in {} sectors",
+                name, bytes, sectors
+            ),
+            AreaDiff::Added(area) => writeln!(
+                report,
+                "  {}: added at {:#X}, size {} KB",
+                name,
+                area.offset,
+                area.size / 1024
+            ),
+            AreaDiff::Removed(area) => writeln!(
+                report,
+                "  {}: removed from {:#X}, size {} KB",
+                name,
+                area.offset,
+                area.size / 1024
+            ),
+            AreaDiff::Moved { old, new, bytes } => writeln!(
+                report,
+                "  {}: moved from {:#X}, size {} KB to {:#X}, size {} KB, {} bytes differ",
+                name,
+                old.offset,
+                old.size / 1024,
+                new.offset,
+                new.size / 1024,
+                bytes
+            ),
+        };
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEN: usize = 0x4_0000;
+
+    fn fmap(areas: &[(&str, usize, usize)]) -> Fmap {
+        Fmap {
+            name: "FLASH".to_string(),
+            areas: areas
+                .iter()
+                .map(|&(name, offset, size)| (name.to_string(), Area { offset, size }))
+                .collect(),
+        }
+    }
+
+    /// Image where every byte is its offset in 4 KB sectors plus `seed`, so copied data can be
+    /// traced back to where it came from
+    fn image(seed: u8) -> Vec<u8> {
+        (0..LEN)
+            .map(|offset| ((offset / 4096) as u8).wrapping_add(seed))
+            .collect()
+    }
+
+    fn policy(text: &str) -> AreaPolicy {
+        let mut policy = AreaPolicy::default();
+        policy.parse(text).unwrap();
+        policy
+    }
+
+    #[test]
+    fn parse() {
+        let policy = policy("# comment\n\nSMMSTORE clear\nCUSTOM  new # trailing\n");
+        assert!(
+            policy
+                .areas
+                .contains(&("SMMSTORE".to_string(), AreaAction::Clear))
+        );
+        assert_eq!(
+            policy.areas.last(),
+            Some(&("CUSTOM".to_string(), AreaAction::New))
+        );
+        // Overriding an area keeps its place
+        assert_eq!(policy.areas.len(), 5);
+        assert_eq!(policy.areas[0].0, "SMMSTORE");
+    }
+
+    #[test]
+    fn parse_invalid() {
+        let mut policy = AreaPolicy::default();
+        assert_eq!(
+            policy.parse("SMMSTORE keep"),
+            Err("invalid line 1: \"SMMSTORE keep\"".to_string())
+        );
+        assert!(policy.parse("\nSMMSTORE").is_err());
+        assert!(policy.parse("SMMSTORE clear now").is_err());
+    }
+
+    #[test]
+    fn preserve_clear_new() {
+        let old_fmap = fmap(&[
+            ("SMMSTORE", 0x1_0000, 0x4000),
+            ("RW_NVRAM", 0x2_0000, 0x2000),
+            ("RW_VPD", 0x3_0000, 0x1000),
+        ]);
+        let new_fmap = fmap(&[
+            ("SMMSTORE", 0x1_0000, 0x4000),
+            ("RW_NVRAM", 0x2_8000, 0x2000),
+            ("RW_VPD", 0x3_0000, 0x2000),
+            ("RO_VPD", 0x3_8000, 0x1000),
+        ]);
+        let mut old = image(0x80);
+        let mut new = image(0);
+        let policy = policy("RO_VPD clear\nRW_VPD new");
+        let results = policy
+            .apply(&old_fmap, &mut old, &new_fmap, &mut new)
+            .unwrap();
+        assert_eq!(
+            results,
+            vec![
+                ("SMMSTORE".to_string(), AreaResult::Copied),
+                ("RO_VPD".to_string(), AreaResult::Cleared),
+                ("RW_VPD".to_string(), AreaResult::New),
+                (
+                    "RW_NVRAM".to_string(),
+                    AreaResult::Moved {
+                        old_offset: 0x2_0000,
+                        new_offset: 0x2_8000
+                    }
+                ),
+            ]
+        );
+        assert_eq!(new[0x1_0000..0x1_4000], old[0x1_0000..0x1_4000]);
+        assert_eq!(new[0x2_8000..0x2_A000], old[0x2_0000..0x2_2000]);
+        assert!(new[0x3_8000..0x3_9000].iter().all(|&b| b == ERASE_BYTE));
+        assert_eq!(new[0x3_0000..0x3_2000], image(0)[0x3_0000..0x3_2000]);
+    }
+
+    #[test]
+    fn resized_and_unknown_areas() {
+        let old_fmap = fmap(&[("SMMSTORE", 0x1_0000, 0x4000), ("OLD_ONLY", 0, 0x1000)]);
+        let new_fmap = fmap(&[("SMMSTORE", 0x1_0000, 0x8000), ("NEW_ONLY", 0, 0x1000)]);
+        let mut old = image(0x80);
+        let mut new = image(0);
+        let policy = policy("OLD_ONLY preserve\nNEW_ONLY preserve\nNOT_AN_AREA clear");
+        let results = policy
+            .apply(&old_fmap, &mut old, &new_fmap, &mut new)
+            .unwrap();
+        assert_eq!(
+            results[0],
+            (
+                "SMMSTORE".to_string(),
+                AreaResult::Resized {
+                    old_size: 0x4000,
+                    new_size: 0x8000
+                }
+            )
+        );
+        assert_eq!(
+            &results[4..],
+            &[
+                ("OLD_ONLY".to_string(), AreaResult::NotInNew),
+                ("NEW_ONLY".to_string(), AreaResult::NotInOld),
+                ("NOT_AN_AREA".to_string(), AreaResult::Missing),
+            ]
+        );
+        // Nothing was changed, and areas in neither firmware are left out of the report
+        assert_eq!(new, image(0));
+        let report = policy_report(&results);
+        assert!(report.contains("SMMSTORE: WARNING"));
+        assert!(!report.contains("NOT_AN_AREA"));
+    }
+
+    #[test]
+    fn overlapping_areas() {
+        // Clearing a parent area after preserving a child area clears the child too
+        let layout = fmap(&[
+            ("RW_SECTION", 0x1_0000, 0x1_0000),
+            ("SMMSTORE", 0x1_8000, 0x4000),
+        ]);
+        let mut old = image(0x80);
+        let mut new = image(0);
+        let results = policy("RW_SECTION clear")
+            .apply(&layout, &mut old, &layout, &mut new)
+            .unwrap();
+        assert_eq!(results[0], ("SMMSTORE".to_string(), AreaResult::Copied));
+        assert_eq!(results[4], ("RW_SECTION".to_string(), AreaResult::Cleared));
+        assert!(new[0x1_0000..0x2_0000].iter().all(|&b| b == ERASE_BYTE));
+
+        // In the other order, the child area is preserved inside the cleared parent
+        let mut policy = AreaPolicy { areas: Vec::new() };
+        policy.set("RW_SECTION", AreaAction::Clear);
+        policy.set("SMMSTORE", AreaAction::Preserve);
+        let mut new = image(0);
+        policy.apply(&layout, &mut old, &layout, &mut new).unwrap();
+        assert!(new[0x1_0000..0x1_8000].iter().all(|&b| b == ERASE_BYTE));
+        assert_eq!(new[0x1_8000..0x1_C000], old[0x1_8000..0x1_C000]);
+    }
+
+    #[test]
+    fn area_outside_image() {
+        let layout = fmap(&[("SMMSTORE", LEN - 0x1000, 0x2000)]);
+        let mut old = image(0x80);
+        let mut new = image(0);
+        assert!(
+            policy("")
+                .apply(&layout, &mut old, &layout, &mut new)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn allowlist_parse() {
+        let allowlist = Allowlist::parse("# comment\nRW_SECTION_A\n\n  SMMSTORE # trailing\n");
+        assert_eq!(allowlist.names, vec!["RW_SECTION_A", "SMMSTORE"]);
+    }
+
+    #[test]
+    fn allowlist_keeps_everything_else() {
+        let layout = fmap(&[
+            ("RW_SECTION_A", 0x1_0000, 0x1_0000),
+            ("SMMSTORE", 0x3_0000, 0x4000),
+        ]);
+        let mut old = image(0x80);
+        let mut new = image(0);
+        let allowlist = Allowlist::parse("SMMSTORE\nRW_SECTION_A");
+        let ranges = allowlist
+            .apply(&layout, &mut old, &layout, &mut new, 4096)
+            .unwrap();
+        assert_eq!(ranges, vec![0x1_0000..0x2_0000, 0x3_0000..0x3_4000]);
+        assert_eq!(new[..0x1_0000], old[..0x1_0000]);
+        assert_eq!(new[0x1_0000..0x2_0000], image(0)[0x1_0000..0x2_0000]);
+        assert_eq!(new[0x2_0000..0x3_0000], old[0x2_0000..0x3_0000]);
+        assert_eq!(new[0x3_0000..0x3_4000], image(0)[0x3_0000..0x3_4000]);
+        assert_eq!(new[0x3_4000..], old[0x3_4000..]);
+    }
+
+    #[test]
+    fn allowlist_overlapping_areas() {
+        let layout = fmap(&[
+            ("RW_SECTION_A", 0x1_0000, 0x1_0000),
+            ("RW_FWID_A", 0x1_F000, 0x1000),
+            ("RW_LEGACY", 0x1_8000, 0x1_0000),
+        ]);
+        let mut old = image(0x80);
+        let mut new = image(0);
+        let allowlist = Allowlist::parse("RW_FWID_A\nRW_SECTION_A\nRW_LEGACY");
+        allowlist
+            .apply(&layout, &mut old, &layout, &mut new, 4096)
+            .unwrap();
+        assert_eq!(new[..0x1_0000], old[..0x1_0000]);
+        assert_eq!(new[0x1_0000..0x2_8000], image(0)[0x1_0000..0x2_8000]);
+        assert_eq!(new[0x2_8000..], old[0x2_8000..]);
+    }
+
+    #[test]
+    fn allowlist_invalid_areas() {
+        let old_fmap = fmap(&[
+            ("RW_SECTION_A", 0x1_0000, 0x1_0000),
+            ("UNALIGNED", 0x2_0800, 0x800),
+        ]);
+        let new_fmap = fmap(&[
+            ("RW_SECTION_A", 0x2_0000, 0x1_0000),
+            ("UNALIGNED", 0x2_0800, 0x800),
+        ]);
+        let mut old = image(0x80);
+        for (text, err) in [
+            (
+                "NOT_AN_AREA",
+                "NOT_AN_AREA is not in both old and new firmware",
+            ),
+            (
+                "RW_SECTION_A",
+                "RW_SECTION_A moves from 0x10000:0x10000 to 0x20000:0x10000",
+            ),
+            ("UNALIGNED", "UNALIGNED is not aligned to 4 KB sectors"),
+        ] {
+            let mut new = image(0);
+            assert_eq!(
+                Allowlist::parse(text).apply(&old_fmap, &mut old, &new_fmap, &mut new, 4096),
+                Err(err.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn allowlist_policy_clears_outside() {
+        let layout = fmap(&[
+            ("RW_SECTION_A", 0x1_0000, 0x1_0000),
+            ("SMMSTORE", 0x3_0000, 0x4000),
+        ]);
+        let mut old = image(0x80);
+        let mut new = image(0);
+        let results = policy("SMMSTORE clear")
+            .apply(&layout, &mut old, &layout, &mut new)
+            .unwrap();
+        assert_eq!(
+            Allowlist::parse("RW_SECTION_A").check_policy(&results),
+            Err(
+                "SMMSTORE is cleared by the FMAP policy, but is not allowed by partial update"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            Allowlist::parse("RW_SECTION_A\nSMMSTORE").check_policy(&results),
+            Ok(())
+        );
+        // Preserved areas are copied from the old firmware either way
+        let results = policy("")
+            .apply(&layout, &mut old, &layout, &mut new)
+            .unwrap();
+        assert_eq!(
+            Allowlist::parse("RW_SECTION_A").check_policy(&results),
+            Ok(())
+        );
+    }
+}
@@ -0,0 +1,18 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Parts of the firmware updater that do not touch hardware, so they can be tested on the host
+
+#![no_std]
+#![allow(clippy::uninlined_format_args)]
+
+extern crate alloc;
+#[cfg(test)]
+extern crate std;
+
+pub mod alias;
+pub mod descriptor;
+pub mod ec_flash;
+pub mod fmap;
+pub mod sector;
+pub mod signature;
+pub mod version;
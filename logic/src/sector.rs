@@ -0,0 +1,529 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+pub const ERASE_BYTE: u8 = 0xFF;
+
+/// Reads the current contents of a flash chip on demand, so the whole chip does not have to be
+/// kept in memory
+pub trait RomReader {
+    /// Fill `buf` with the contents starting at `offset`
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> core::result::Result<(), String>;
+}
+
+/// Whole image in memory, for tests of code that reads the old firmware
+#[cfg(test)]
+impl RomReader for Vec<u8> {
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> core::result::Result<(), String> {
+        buf.copy_from_slice(&self[offset..offset + buf.len()]);
+        Ok(())
+    }
+}
+
+/// Erases and writes a flash chip one sector at a time
+pub trait RomWriter: RomReader {
+    fn erase_sector(&mut self, address: usize) -> core::result::Result<(), String>;
+    /// Write a whole sector, which must be erased
+    fn write_sector(&mut self, address: usize, data: &[u8]) -> core::result::Result<(), String>;
+}
+
+/// Read back a written sector and compare it with the data that was written
+fn verify_sector(
+    rom: &mut dyn RomWriter,
+    address: usize,
+    data: &[u8],
+    buf: &mut [u8],
+) -> core::result::Result<(), String> {
+    let buf = &mut buf[..data.len()];
+    rom.read_at(address, buf)?;
+    match buf.iter().zip(data.iter()).position(|(a, b)| a != b) {
+        Some(i) => Err(format!(
+            "{:X} is {:X} instead of {:X}",
+            address + i,
+            buf[i],
+            data[i]
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Work needed to turn the old contents of a sector into the new contents
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Sector {
+    pub address: usize,
+    /// Old data is not erased, so the sector must be erased
+    pub erase: bool,
+    /// New data is not erased, so the sector must be written
+    pub write: bool,
+}
+
+impl Sector {
+    fn op(&self) -> &'static str {
+        match (self.erase, self.write) {
+            (true, true) => "erase and write",
+            (true, false) => "erase",
+            (false, true) => "write",
+            (false, false) => "skip",
+        }
+    }
+}
+
+/// Rough time per operation, used to estimate how long flashing takes
+pub struct Timing {
+    pub erase_us: u64,
+    pub block_erase_us: u64,
+    pub write_us: u64,
+    pub read_us_per_mb: u64,
+}
+
+/// Sectors that differ between the old and new contents of a flash chip
+pub struct Plan {
+    pub sector_size: usize,
+    pub len: usize,
+    pub sectors: Vec<Sector>,
+    /// Size of blocks erased with one command, if supported
+    pub block_size: usize,
+    /// Addresses of blocks where every sector needs erasing
+    pub blocks: Vec<usize>,
+}
+
+impl Plan {
+    pub fn new(old: &[u8], new: &[u8], sector_size: usize) -> Self {
+        let mut sectors = Vec::new();
+        for (i, (chunk, new_chunk)) in old
+            .chunks(sector_size)
+            .zip(new.chunks(sector_size))
+            .enumerate()
+        {
+            // Data matches, meaning sector can be skipped
+            if chunk == new_chunk {
+                continue;
+            }
+
+            sectors.push(Sector {
+                address: i * sector_size,
+                erase: chunk.iter().any(|&b| b != ERASE_BYTE),
+                write: new_chunk.iter().any(|&b| b != ERASE_BYTE),
+            });
+        }
+
+        Self {
+            sector_size,
+            len: new.len(),
+            sectors,
+            block_size: 0,
+            blocks: Vec::new(),
+        }
+    }
+
+    /// Compare the new contents against the chip one sector at a time, calling `progress` with
+    /// the number of bytes compared so far
+    pub fn read(
+        old: &mut dyn RomReader,
+        new: &[u8],
+        sector_size: usize,
+        progress: &mut dyn FnMut(usize),
+    ) -> core::result::Result<Self, String> {
+        let mut sectors = Vec::new();
+        let mut chunk = vec![0; sector_size];
+        for (i, new_chunk) in new.chunks(sector_size).enumerate() {
+            let address = i * sector_size;
+            let chunk = &mut chunk[..new_chunk.len()];
+            old.read_at(address, chunk)?;
+
+            // Data matches, meaning sector can be skipped
+            if chunk != new_chunk {
+                sectors.push(Sector {
+                    address,
+                    erase: chunk.iter().any(|&b| b != ERASE_BYTE),
+                    write: new_chunk.iter().any(|&b| b != ERASE_BYTE),
+                });
+            }
+
+            progress(address + new_chunk.len());
+        }
+
+        Ok(Self {
+            sector_size,
+            len: new.len(),
+            sectors,
+            block_size: 0,
+            blocks: Vec::new(),
+        })
+    }
+
+    /// Move sectors overlapping each range to the end, in the order the ranges are given, so
+    /// that they are written only after every other sector. Returns the index of the first
+    /// moved sector.
+    pub fn order_last(&mut self, ranges: &[core::ops::Range<usize>]) -> usize {
+        let sector_size = self.sector_size;
+        let overlaps = |sector: &Sector, range: &core::ops::Range<usize>| {
+            sector.address < range.end && range.start < sector.address + sector_size
+        };
+
+        let (mut sectors, mut rest): (Vec<Sector>, Vec<Sector>) = self
+            .sectors
+            .iter()
+            .partition(|sector| !ranges.iter().any(|range| overlaps(sector, range)));
+        let first = sectors.len();
+        for range in ranges.iter() {
+            let (last, remaining): (Vec<Sector>, Vec<Sector>) =
+                rest.iter().partition(|sector| overlaps(sector, range));
+            sectors.extend(last);
+            rest = remaining;
+        }
+        self.sectors = sectors;
+        first
+    }
+
+    /// Make sure the sector at `address` is written last, adding it to the plan if anything
+    /// else changes. Returns the index of the sector in the plan.
+    pub fn signature_last(&mut self, address: usize, old: &[u8], new: &[u8]) -> Option<usize> {
+        if self.sectors.is_empty() {
+            return None;
+        }
+
+        let address = address - address % self.sector_size;
+        if !self.sectors.iter().any(|sector| sector.address == address) {
+            let end = (address + self.sector_size).min(new.len());
+            self.sectors.push(Sector {
+                address,
+                erase: old[address..end].iter().any(|&b| b != ERASE_BYTE),
+                write: new[address..end].iter().any(|&b| b != ERASE_BYTE),
+            });
+            self.sectors.sort_by_key(|sector| sector.address);
+        }
+
+        self.order_last(core::slice::from_ref(&(address..address + 1)));
+        Some(self.sectors.len() - 1)
+    }
+
+    /// Write the plan, erasing the signature sector from `signature_last` first and writing it
+    /// only after every other sector is written and verified, so an interrupted write never
+    /// leaves an image with a valid signature. `progress` is called with the address of each
+    /// sector before it is written.
+    pub fn program_signature_last(
+        &self,
+        rom: &mut dyn RomWriter,
+        new: &[u8],
+        progress: &mut dyn FnMut(usize),
+    ) -> core::result::Result<(), String> {
+        let (signature, sectors) = match self.sectors.split_last() {
+            Some(some) => some,
+            None => return Ok(()),
+        };
+        let chunk = |address: usize| &new[address..(address + self.sector_size).min(new.len())];
+
+        if signature.erase {
+            rom.erase_sector(signature.address)?;
+        }
+
+        for sector in sectors.iter() {
+            progress(sector.address);
+            if sector.erase {
+                rom.erase_sector(sector.address)?;
+            }
+            if sector.write {
+                rom.write_sector(sector.address, chunk(sector.address))?;
+            }
+        }
+
+        let mut buf = vec![0; self.sector_size];
+        for sector in sectors.iter() {
+            verify_sector(rom, sector.address, chunk(sector.address), &mut buf)?;
+        }
+
+        if signature.write {
+            progress(signature.address);
+            rom.write_sector(signature.address, chunk(signature.address))?;
+        }
+        verify_sector(rom, signature.address, chunk(signature.address), &mut buf)
+    }
+
+    /// Find aligned blocks where every sector needs erasing and is written in sequence, so
+    /// they can be erased with one command
+    pub fn coalesce(&mut self, block_size: usize) {
+        let count = block_size / self.sector_size;
+        self.block_size = block_size;
+        self.blocks.clear();
+
+        let mut i = 0;
+        while i < self.sectors.len() {
+            let base = self.sectors[i].address;
+            let whole = base % block_size == 0
+                && self.sectors.len() - i >= count
+                && self.sectors[i..i + count]
+                    .iter()
+                    .enumerate()
+                    .all(|(j, sector)| {
+                        sector.erase && sector.address == base + j * self.sector_size
+                    });
+            if whole {
+                self.blocks.push(base);
+                i += count;
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Address of the block erase covering this sector, if there is one
+    pub fn block(&self, address: usize) -> Option<usize> {
+        if self.block_size == 0 {
+            return None;
+        }
+        let base = address - address % self.block_size;
+        self.blocks.contains(&base).then_some(base)
+    }
+
+    pub fn erases(&self) -> usize {
+        self.sectors
+            .iter()
+            .filter(|sector| sector.erase && self.block(sector.address).is_none())
+            .count()
+    }
+
+    pub fn writes(&self) -> usize {
+        self.sectors.iter().filter(|sector| sector.write).count()
+    }
+
+    /// Estimated time in seconds to erase, write, and verify
+    pub fn estimate(&self, timing: &Timing) -> u64 {
+        let us = self.erases() as u64 * timing.erase_us
+            + self.blocks.len() as u64 * timing.block_erase_us
+            + self.writes() as u64 * timing.write_us
+            + (self.len as u64).div_ceil(1024 * 1024) * timing.read_us_per_mb;
+        us.div_ceil(1_000_000)
+    }
+
+    /// Estimated time in seconds if every sector was erased on its own
+    pub fn estimate_sectors(&self, timing: &Timing) -> u64 {
+        let us = self.sectors.iter().filter(|sector| sector.erase).count() as u64 * timing.erase_us
+            + self.writes() as u64 * timing.write_us
+            + (self.len as u64).div_ceil(1024 * 1024) * timing.read_us_per_mb;
+        us.div_ceil(1_000_000)
+    }
+
+    /// Describe ranges of sectors that would be changed
+    pub fn report(&self, name: &str, timing: &Timing) -> String {
+        let mut report = String::new();
+        let mut i = 0;
+        while i < self.sectors.len() {
+            let start = self.sectors[i];
+            let mut end = i;
+            while end + 1 < self.sectors.len() {
+                let next = self.sectors[end + 1];
+                if next.address != self.sectors[end].address + self.sector_size
+                    || next.op() != start.op()
+                {
+                    break;
+                }
+                end += 1;
+            }
+
+            let _ = writeln!(
+                report,
+                "{}: {:#08X}:{:#08X}: {} ({} sectors)",
+                name,
+                start.address,
+                self.sectors[end].address + self.sector_size - 1,
+                start.op(),
+                end - i + 1
+            );
+
+            i = end + 1;
+        }
+
+        let _ = writeln!(
+            report,
+            "{}: {} of {} sectors of {} KB differ, {} erased, {} written, about {} seconds",
+            name,
+            self.sectors.len(),
+            self.len.div_ceil(self.sector_size),
+            self.sector_size / 1024,
+            self.erases(),
+            self.writes(),
+            self.estimate(timing)
+        );
+        if !self.blocks.is_empty() {
+            let _ = writeln!(
+                report,
+                "{}: {} blocks of {} KB erased at once, about {} seconds with only sector erases",
+                name,
+                self.blocks.len(),
+                self.block_size / 1024,
+                self.estimate_sectors(timing)
+            );
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    const SECTOR_SIZE: usize = 4096;
+    const SIGNATURE: usize = 0x40;
+
+    /// SPI ROM in memory that fails every operation after `budget` have succeeded, like a
+    /// flash that loses power
+    struct SimRom {
+        data: Vec<u8>,
+        budget: usize,
+        log: Vec<(&'static str, usize)>,
+    }
+
+    impl SimRom {
+        fn new(data: &[u8], budget: usize) -> Self {
+            Self {
+                data: data.to_vec(),
+                budget,
+                log: Vec::new(),
+            }
+        }
+
+        fn op(&mut self, name: &'static str, address: usize) -> core::result::Result<(), String> {
+            if self.budget == 0 {
+                return Err("power lost".to_string());
+            }
+            self.budget -= 1;
+            self.log.push((name, address));
+            Ok(())
+        }
+    }
+
+    impl RomReader for SimRom {
+        fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> core::result::Result<(), String> {
+            buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+            Ok(())
+        }
+    }
+
+    impl RomWriter for SimRom {
+        fn erase_sector(&mut self, address: usize) -> core::result::Result<(), String> {
+            self.op("erase", address)?;
+            self.data[address..address + SECTOR_SIZE].fill(ERASE_BYTE);
+            Ok(())
+        }
+
+        fn write_sector(
+            &mut self,
+            address: usize,
+            data: &[u8],
+        ) -> core::result::Result<(), String> {
+            self.op("write", address)?;
+            let sector = &mut self.data[address..address + data.len()];
+            if sector.iter().any(|&b| b != ERASE_BYTE) {
+                return Err(format!("write to sector {:X} that is not erased", address));
+            }
+            sector.copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    /// Image of `sectors` sectors, each filled with `fill` plus its index, and a signature
+    fn image(sectors: usize, fill: u8) -> Vec<u8> {
+        let mut data = vec![0; sectors * SECTOR_SIZE];
+        for (i, chunk) in data.chunks_mut(SECTOR_SIZE).enumerate() {
+            chunk.fill(fill.wrapping_add(i as u8));
+        }
+        data[SIGNATURE..SIGNATURE + 4].copy_from_slice(&[0xA5, 0xA5, 0x85, 0x12]);
+        data
+    }
+
+    fn signature_valid(data: &[u8]) -> bool {
+        data[SIGNATURE..SIGNATURE + 4] == [0xA5, 0xA5, 0x85, 0x12]
+    }
+
+    fn plan(old: &[u8], new: &[u8]) -> Plan {
+        let mut plan = Plan::new(old, new, SECTOR_SIZE);
+        plan.signature_last(SIGNATURE, old, new);
+        plan
+    }
+
+    #[test]
+    fn signature_sector_is_erased_first_and_written_last() {
+        let old = image(8, 0x10);
+        let new = image(8, 0x20);
+        let plan = plan(&old, &new);
+
+        let mut rom = SimRom::new(&old, usize::MAX);
+        plan.program_signature_last(&mut rom, &new, &mut |_| ())
+            .unwrap();
+
+        assert_eq!(rom.data, new);
+        assert_eq!(rom.log.first(), Some(&("erase", 0)));
+        assert_eq!(rom.log.last(), Some(&("write", 0)));
+        assert_eq!(
+            rom.log.iter().filter(|(_, address)| *address == 0).count(),
+            2
+        );
+    }
+
+    #[test]
+    fn unchanged_signature_sector_is_rewritten_last() {
+        let old = image(8, 0x10);
+        let mut new = old.clone();
+        new[5 * SECTOR_SIZE..6 * SECTOR_SIZE].fill(0x55);
+        let plan = plan(&old, &new);
+
+        assert_eq!(plan.sectors.len(), 2);
+        assert_eq!(plan.sectors.last().map(|sector| sector.address), Some(0));
+
+        let mut rom = SimRom::new(&old, usize::MAX);
+        plan.program_signature_last(&mut rom, &new, &mut |_| ())
+            .unwrap();
+        assert_eq!(rom.data, new);
+        assert_eq!(
+            rom.log,
+            vec![
+                ("erase", 0),
+                ("erase", 5 * SECTOR_SIZE),
+                ("write", 5 * SECTOR_SIZE),
+                ("write", 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn identical_image_is_not_written() {
+        let old = image(8, 0x10);
+        let mut plan = Plan::new(&old, &old, SECTOR_SIZE);
+        assert_eq!(plan.signature_last(SIGNATURE, &old, &old), None);
+
+        let mut rom = SimRom::new(&old, 0);
+        plan.program_signature_last(&mut rom, &old, &mut |_| ())
+            .unwrap();
+        assert_eq!(rom.data, old);
+    }
+
+    #[test]
+    fn interrupted_flash_never_has_valid_signature() {
+        let old = image(8, 0x10);
+        let new = image(8, 0x20);
+        let plan = plan(&old, &new);
+
+        // Every point where power can be lost, up to and including completion
+        let ops = 2 * plan.sectors.len();
+        for budget in 0..=ops {
+            let mut rom = SimRom::new(&old, budget);
+            let result = plan.program_signature_last(&mut rom, &new, &mut |_| ());
+            if rom.data == new {
+                assert!(result.is_ok());
+            } else if rom.data != old {
+                assert!(result.is_err());
+                assert!(
+                    !signature_valid(&rom.data),
+                    "half written image has a valid signature after {} operations",
+                    budget
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use ed25519_compact::{PublicKey, Signature};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// No trusted keys, so nothing can be verified
+    NoKeys,
+    /// Trusted keys are not a list of raw 32 byte keys
+    InvalidKeys,
+    /// Signature is not a raw 64 byte Ed25519 signature
+    InvalidSignature,
+    /// Signature does not match any trusted key
+    Mismatch,
+}
+
+/// Trusted keys are a non-empty list of raw 32 byte keys, checked when they are compiled in
+pub const fn keys_valid(keys: &[u8]) -> bool {
+    !keys.is_empty() && keys.len() % PublicKey::BYTES == 0
+}
+
+/// Verify a detached Ed25519 signature of `data` against trusted keys, given as concatenated
+/// raw 32 byte keys. Verification fails if there are no keys.
+pub fn verify(keys: &[u8], data: &[u8], signature: &[u8]) -> Result<(), Error> {
+    if keys.is_empty() {
+        return Err(Error::NoKeys);
+    }
+    if keys.len() % PublicKey::BYTES != 0 {
+        return Err(Error::InvalidKeys);
+    }
+
+    let signature = Signature::from_slice(signature).map_err(|_| Error::InvalidSignature)?;
+    for key in keys.chunks_exact(PublicKey::BYTES) {
+        let public_key = PublicKey::from_slice(key).map_err(|_| Error::InvalidKeys)?;
+        if public_key.verify(data, &signature).is_ok() {
+            return Ok(());
+        }
+    }
+    Err(Error::Mismatch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // RFC 8032 section 7.1, TEST 1 and TEST 2
+    const KEY_1: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+    const SIGNATURE_1: &str = "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b";
+    const KEY_2: &str = "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c";
+    const MESSAGE_2: &[u8] = &[0x72];
+    const SIGNATURE_2: &str = "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00";
+
+    #[test]
+    fn good_signature() {
+        assert_eq!(verify(&hex(KEY_1), &[], &hex(SIGNATURE_1)), Ok(()));
+        assert_eq!(verify(&hex(KEY_2), MESSAGE_2, &hex(SIGNATURE_2)), Ok(()));
+    }
+
+    #[test]
+    fn any_trusted_key() {
+        let keys = [hex(KEY_1), hex(KEY_2)].concat();
+        assert_eq!(verify(&keys, &[], &hex(SIGNATURE_1)), Ok(()));
+        assert_eq!(verify(&keys, MESSAGE_2, &hex(SIGNATURE_2)), Ok(()));
+    }
+
+    #[test]
+    fn tampered_data() {
+        assert_eq!(
+            verify(&hex(KEY_2), &[0x73], &hex(SIGNATURE_2)),
+            Err(Error::Mismatch)
+        );
+        assert_eq!(
+            verify(&hex(KEY_1), &[0x00], &hex(SIGNATURE_1)),
+            Err(Error::Mismatch)
+        );
+    }
+
+    #[test]
+    fn tampered_signature() {
+        let mut signature = hex(SIGNATURE_2);
+        signature[0] ^= 1;
+        assert_eq!(
+            verify(&hex(KEY_2), MESSAGE_2, &signature),
+            Err(Error::Mismatch)
+        );
+    }
+
+    #[test]
+    fn wrong_key() {
+        assert_eq!(
+            verify(&hex(KEY_1), MESSAGE_2, &hex(SIGNATURE_2)),
+            Err(Error::Mismatch)
+        );
+        assert_eq!(
+            verify(&hex(KEY_2), &[], &hex(SIGNATURE_1)),
+            Err(Error::Mismatch)
+        );
+    }
+
+    #[test]
+    fn key_list() {
+        assert!(keys_valid(&hex(KEY_1)));
+        assert!(keys_valid(&[hex(KEY_1), hex(KEY_2)].concat()));
+        assert!(!keys_valid(&[]));
+        assert!(!keys_valid(&hex(KEY_1)[..31]));
+        assert!(!keys_valid(
+            &[hex(KEY_1), hex(KEY_2)[..1].to_vec()].concat()
+        ));
+    }
+
+    #[test]
+    fn fails_closed() {
+        assert_eq!(
+            verify(&[], MESSAGE_2, &hex(SIGNATURE_2)),
+            Err(Error::NoKeys)
+        );
+        assert_eq!(
+            verify(&hex(KEY_2)[..31], MESSAGE_2, &hex(SIGNATURE_2)),
+            Err(Error::InvalidKeys)
+        );
+        assert_eq!(
+            verify(&hex(KEY_2), MESSAGE_2, &[]),
+            Err(Error::InvalidSignature)
+        );
+        assert_eq!(
+            verify(&hex(KEY_2), MESSAGE_2, &hex(SIGNATURE_2)[..63]),
+            Err(Error::InvalidSignature)
+        );
+    }
+}
@@ -0,0 +1,122 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+#[derive(Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum Version {
+    /// Date based versions, like coreboot `2024-05-01_abcdef` or System76 EC `2024-05-01_abcdef`
+    Date(u32, u32, u32),
+    /// Dotted versions, like legacy EC `1.07.02`
+    Numeric(Vec<u32>),
+}
+
+impl Version {
+    fn parse(s: &str) -> Option<Self> {
+        Self::parse_date(s).or_else(|| Self::parse_numeric(s))
+    }
+
+    fn parse_date(s: &str) -> Option<Self> {
+        let date = s.get(..10)?;
+        let mut parts = date.split(['-', '/']);
+        let mut next = |len: usize| -> Option<u32> {
+            let part = parts.next()?;
+            if part.len() == len && part.bytes().all(|b| b.is_ascii_digit()) {
+                part.parse().ok()
+            } else {
+                None
+            }
+        };
+        let year = next(4)?;
+        let month = next(2)?;
+        let day = next(2)?;
+        Some(Self::Date(year, month, day))
+    }
+
+    fn parse_numeric(s: &str) -> Option<Self> {
+        let s = s.trim_start_matches(['v', 'V']);
+        let end = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(s.len());
+        let mut parts = Vec::new();
+        for part in s[..end].split('.') {
+            parts.push(part.parse().ok()?);
+        }
+        if parts.len() < 2 {
+            return None;
+        }
+        Some(Self::Numeric(parts))
+    }
+}
+
+/// Compare the new firmware version to the current one, if they use the same scheme. The commit
+/// hash of date based versions is ignored, so builds from the same day compare as equal.
+pub fn compare(current: &str, new: &str) -> Option<Ordering> {
+    match (Version::parse(current)?, Version::parse(new)?) {
+        (current @ Version::Date(..), new @ Version::Date(..)) => Some(new.cmp(&current)),
+        (current @ Version::Numeric(_), new @ Version::Numeric(_)) => Some(new.cmp(&current)),
+        _ => None,
+    }
+}
+
+/// Check if flashing the new version would be a downgrade
+pub fn is_downgrade(current: &str, new: &str) -> bool {
+    compare(current, new) == Some(Ordering::Less)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn date_versions() {
+        assert_eq!(
+            compare("2024-05-01_abcdef0", "2024-06-01_1234567"),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(
+            compare("2024-05-01_abcdef0", "2023-12-31_1234567"),
+            Some(Ordering::Less)
+        );
+        assert!(is_downgrade("2024-05-01_abcdef0", "2023-12-31_1234567"));
+        assert!(!is_downgrade("2024-05-01_abcdef0", "2024-06-01_1234567"));
+    }
+
+    #[test]
+    fn date_versions_ignore_commit() {
+        assert_eq!(
+            compare("2024-05-01_abcdef0", "2024-05-01_1234567"),
+            Some(Ordering::Equal)
+        );
+        assert_eq!(compare("2024/05/01", "2024-05-01"), Some(Ordering::Equal));
+        assert!(!is_downgrade("2024-05-01_abcdef0", "2024-05-01_1234567"));
+    }
+
+    #[test]
+    fn numeric_versions() {
+        assert_eq!(compare("1.07.02", "1.07.10"), Some(Ordering::Greater));
+        assert_eq!(compare("1.07.02", "1.7.2"), Some(Ordering::Equal));
+        assert_eq!(compare("v1.10", "1.9"), Some(Ordering::Less));
+        assert_eq!(compare("1.07", "1.07.01"), Some(Ordering::Greater));
+        assert!(is_downgrade("1.07.10", "1.07.02"));
+        assert!(!is_downgrade("1.07.02", "1.07.02"));
+    }
+
+    #[test]
+    fn mixed_versions_are_not_compared() {
+        assert_eq!(compare("2024-05-01_abcdef0", "1.07.02"), None);
+        assert_eq!(compare("1.07.02", "2024-05-01_abcdef0"), None);
+        assert!(!is_downgrade("2024-05-01_abcdef0", "1.07.02"));
+        assert!(!is_downgrade("1.07.02", "2024-05-01_abcdef0"));
+    }
+
+    #[test]
+    fn unparsable_versions_are_not_compared() {
+        for version in ["", "unknown", "7", "1..2", "2024-5-01", "2024-05-1x"] {
+            assert_eq!(compare(version, "1.07.02"), None, "{:?}", version);
+            assert_eq!(compare("1.07.02", version), None, "{:?}", version);
+            assert!(!is_downgrade("2024-05-01_abcdef0", version));
+            assert!(!is_downgrade(version, "2024-05-01_abcdef0"));
+        }
+    }
+}
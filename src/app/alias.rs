@@ -0,0 +1,35 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use core::str;
+use std::fs::load;
+use std::prelude::*;
+
+pub use system76_firmware_update_logic::alias::*;
+
+use super::{ECMODELS, signature};
+
+/// Load the built in table, with rows from the firmware bundle. The bundle rows must be signed
+/// like firmware images, and replace the built in rows for the board names they list.
+pub fn load_table() -> Result<AliasTable> {
+    let mut table = AliasTable::builtin().map_err(|err| {
+        println!("EC models: built in table: {}", err);
+        Status::LOAD_ERROR
+    })?;
+
+    if let Ok(data) = load(ECMODELS) {
+        signature::verify(ECMODELS, &data)?;
+        let text = str::from_utf8(&data).map_err(|_| Status::LOAD_ERROR)?;
+        let extra = AliasTable::parse(text).map_err(|err| {
+            println!("EC models: {}", err);
+            Status::LOAD_ERROR
+        })?;
+        for name in table.extend(extra) {
+            println!(
+                "EC models: bundle rows for {} replace the built in rows",
+                name
+            );
+        }
+    }
+
+    Ok(table)
+}
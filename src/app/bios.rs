@@ -1,11 +1,10 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
-use alloc::collections::BTreeMap;
 use alloc::string::String;
 use core::arch::asm;
+use core::cell::{Cell, RefCell};
 use core::char;
 use core::ptr;
-use coreboot_fs::Rom;
 use ecflash::EcFlash;
 use intel_spi::{HsfStsCtl, Spi, SpiDev};
 use plain::Plain;
@@ -14,62 +13,141 @@ use std::prelude::*;
 use std::uefi::reset::ResetType;
 use std::vars::{get_boot_item, get_boot_order, set_boot_item, set_boot_order};
 
+use super::capsule::{self, Capsule};
+use super::descriptor::{self, Descriptor};
+use super::erase::{self, SpiCycles};
+use super::fmap::{self, Fmap};
+use super::journal::Journal;
+use super::protection::SpiProtection;
+use super::sector::{self, Plan, RomReader, Sector, Stopwatch, Timing};
 use super::{
-    Component, FIRMWARECAP, FIRMWAREDIR, FIRMWARENSH, FIRMWAREROM, H2OFFT, IFLASHV, UEFIFLASH,
-    UefiMapper, cmos, pci_mcfg, shell,
+    ALLOWUNKNOWNBOARD, BACKUPDIR, Component, DUMPDIR, FIRMWARECAP, FIRMWAREDIR, FIRMWARENSH,
+    FIRMWARERESTORE, FIRMWAREROM, FMAPLOG, H2OFFT, IFLASHV, JOURNALROM, TIMINGLOG, UEFIFLASH,
+    UefiMapper, cbfs, cmos, confirm_unsigned, file, pci_mcfg, shell, signature,
 };
 
-fn copy_region(
-    region: intelflash::RegionKind,
-    old_data: &[u8],
-    new_data: &mut [u8],
-) -> core::result::Result<bool, String> {
-    let old_opt = intelflash::Rom::new(old_data)?.get_region_base_limit(region)?;
-    let new_opt = intelflash::Rom::new(new_data)?.get_region_base_limit(region)?;
-
-    if old_opt.is_none() && new_opt.is_none() {
-        // Neither ROM has this region, so ignore it
-        return Ok(false);
-    }
+static SPI_TIMING: Timing = Timing {
+    erase_us: 50_000,
+    block_erase_us: 300_000,
+    write_us: 20_000,
+    read_us_per_mb: 250_000,
+};
 
-    let old = match old_opt {
-        Some((base, limit)) => {
-            if base < limit && limit < old_data.len() {
-                &old_data[base..limit + 1]
-            } else {
-                return Err(format!("old region {:#X}:{:#X} is invalid", base, limit));
-            }
+/// Size of the top of the ROM holding the reset vector and bootblock, when there is no
+/// BOOTBLOCK area in the FMAP
+const BOOTBLOCK_SIZE: usize = 64 * 1024;
+/// Sectors between progress updates in the journal, to limit writes to the ESP
+const JOURNAL_INTERVAL: usize = 256;
+
+/// Ranges that must be written last, in order, to keep the old firmware bootable for as long as
+/// possible: the FMAP, the bootblock, and the flash descriptor
+fn critical_ranges(new: &[u8], new_fmap: Option<&Fmap>) -> Vec<core::ops::Range<usize>> {
+    let mut ranges = Vec::new();
+
+    if let Some(fmap) = new_fmap {
+        if let Some(area) = fmap.areas.get("FMAP") {
+            ranges.push(area.range());
         }
-        None => return Err("missing old region".to_string()),
-    };
+    }
 
-    let new = match new_opt {
-        Some((base, limit)) => {
-            if base < limit && limit < new_data.len() {
-                &mut new_data[base..limit + 1]
-            } else {
-                return Err(format!("new region {:#X}:{:#X} is invalid", base, limit));
+    match new_fmap.and_then(|fmap| fmap.areas.get("BOOTBLOCK")) {
+        Some(area) => ranges.push(area.range()),
+        None => ranges.push(new.len().saturating_sub(BOOTBLOCK_SIZE)..new.len()),
+    }
+
+    if let Some(region) = descriptor::new(new)
+        .ok()
+        .flatten()
+        .and_then(|new_descriptor| new_descriptor.region(0).copied())
+    {
+        ranges.push(region.range());
+    }
+
+    ranges
+}
+
+/// Largest FMAP read from the old firmware, enough for every area coreboot defines
+const FMAP_MAX_SIZE: usize = 8 * 1024;
+
+/// Attempts for a single SPI operation or sector before giving up
+const SPI_RETRIES: usize = 3;
+/// Retries allowed across the whole flash, so a failing chip is not hammered sector by sector
+const SPI_RETRY_BUDGET: usize = 16;
+
+/// Read `buf.len()` bytes from the SPI ROM, retrying controller errors
+fn spi_read_exact(
+    spi: &mut SpiDev<'static, UefiMapper>,
+    address: usize,
+    buf: &mut [u8],
+) -> core::result::Result<(), String> {
+    let mut count = 0;
+    let mut attempts = 0;
+    while count < buf.len() {
+        match spi.read(address + count, &mut buf[count..]) {
+            Ok(0) => return Err(format!("read at {:#08X} returned no data", address + count)),
+            Ok(read) => count += read,
+            Err(err) => {
+                attempts += 1;
+                if attempts >= SPI_RETRIES {
+                    return Err(format!(
+                        "read at {:#08X} failed: {:?}",
+                        address + count,
+                        err
+                    ));
+                }
             }
         }
-        None => return Err("missing new region".to_string()),
-    };
+    }
+    Ok(())
+}
 
-    if old.len() != new.len() {
+/// Erase and write one sector, then read it back to check the contents
+fn spi_write_sector(
+    spi: &mut SpiDev<'static, UefiMapper>,
+    address: usize,
+    new_chunk: &[u8],
+    erase: bool,
+    write: bool,
+) -> core::result::Result<(), String> {
+    if erase {
+        spi.erase(address)
+            .map_err(|err| format!("erase failed: {:?}", err))?;
+    }
+    if write {
+        spi.write(address, new_chunk)
+            .map_err(|err| format!("write failed: {:?}", err))?;
+    }
+
+    let mut buf = vec![0; new_chunk.len()];
+    spi_read_exact(spi, address, &mut buf)?;
+    if let Some(i) = buf.iter().zip(new_chunk.iter()).position(|(a, b)| a != b) {
         return Err(format!(
-            "old region size {} does not match new region size {}",
-            old.len(),
-            new.len()
+            "read back {:#x} at {:#08X}, expected {:#x}",
+            buf[i],
+            address + i,
+            new_chunk[i]
         ));
     }
 
-    new.copy_from_slice(old);
-    Ok(true)
+    Ok(())
+}
+
+impl RomReader for SpiDev<'static, UefiMapper> {
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> core::result::Result<(), String> {
+        spi_read_exact(self, offset, buf)
+    }
 }
 
 pub struct BiosComponent {
     capsule: bool,
+    restore: bool,
     bios_vendor: String,
     bios_version: String,
+    new_version: RefCell<String>,
+    /// Image checked by validate, kept so it is only loaded once
+    image: RefCell<Option<Vec<u8>>>,
+    /// Capsule can be submitted with UpdateCapsule instead of vendor tools
+    native_capsule: Cell<bool>,
     system_version: String,
     manufacturer: String,
 }
@@ -77,6 +155,7 @@ pub struct BiosComponent {
 impl BiosComponent {
     pub fn new() -> BiosComponent {
         let capsule = find(FIRMWARECAP).is_ok();
+        let restore = find(FIRMWARERESTORE).is_ok();
 
         let mut bios_vendor = String::new();
         let mut bios_version = String::new();
@@ -125,8 +204,12 @@ impl BiosComponent {
 
         BiosComponent {
             capsule,
+            restore,
             bios_vendor,
             bios_version,
+            new_version: RefCell::new(String::new()),
+            image: RefCell::new(None),
+            native_capsule: Cell::new(false),
             system_version,
             manufacturer,
         }
@@ -165,6 +248,553 @@ impl BiosComponent {
         }
     }
 
+    fn file_name(&self) -> &str {
+        self.path().rsplit('\\').next().unwrap_or("")
+    }
+
+    /// Start a backup of the current data, written as the chip is read
+    fn backup(&self) -> Result<file::VerifiedWriter> {
+        let path = format!(
+            "{}\\{}-{}.rom",
+            BACKUPDIR,
+            file::sanitize(&self.system_version),
+            file::sanitize(&self.bios_version)
+        );
+
+        println!("SPI BACKUP: {}", path);
+        match file::create_dir(BACKUPDIR).and_then(|()| file::VerifiedWriter::create(&path)) {
+            Ok(writer) => Ok(writer),
+            Err(err) => {
+                println!("SPI BACKUP: failed to write backup: {:?}", err);
+                Err(err)
+            }
+        }
+    }
+
+    /// Flash one sector of the plan, retrying from an erase if it does not read back correctly
+    fn flash_sector(
+        spi: &mut SpiDev<'static, UefiMapper>,
+        sector: &Sector,
+        new_chunk: &[u8],
+        budget: &mut usize,
+    ) -> Result<()> {
+        let mut erase = sector.erase;
+        let mut attempts = 0;
+        loop {
+            let err = match spi_write_sector(spi, sector.address, new_chunk, erase, sector.write) {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+
+            attempts += 1;
+            if attempts >= SPI_RETRIES || *budget == 0 {
+                println!(
+                    "\nSPI WRITE: failed at {:#08X} after {} attempts: {}",
+                    sector.address, attempts, err
+                );
+                return Err(Status::ABORTED);
+            }
+            *budget -= 1;
+
+            println!("\nSPI WRITE: retrying {:#08X}: {}", sector.address, err);
+            // The sector contents are unknown after a failure, so always start from an erase
+            erase = true;
+        }
+    }
+
+    /// Flash the SPI ROM directly, or only print what would be flashed if `dry_run` is set
+    fn flash_spi(
+        &self,
+        spi: &mut SpiDev<'static, UefiMapper>,
+        hsfsts_ctl: HsfStsCtl,
+        dry_run: bool,
+    ) -> Result<()> {
+        // Use the image checked by validate, which is only loaded again if validate did not run
+        let mut new = match self.image.take() {
+            Some(image) => image,
+            None => load(self.path())?,
+        };
+        println!("SPI FILE: {} MB", new.len() / (1024 * 1024));
+
+        // Resume a flash of the same image that was interrupted, with the image that was being
+        // written, as areas copied from the old firmware may already be overwritten on the chip
+        let hash = file::sha256(&new);
+        if let Some(journal) = Journal::load().filter(|journal| journal.hash == hash) {
+            return Self::resume_spi(spi, hsfsts_ctl, journal, dry_run);
+        }
+
+        // Backups are not signed, so flashing one must be confirmed at the machine
+        if self.restore && !dry_run && !confirm_unsigned(self.path(), &hash)? {
+            println!("Not restoring, nothing was written");
+            return Err(Status::ACCESS_DENIED);
+        }
+
+        // Grab new FMAP areas, if they exist
+        let new_fmap = fmap::new(&new);
+        if let Some(fmap) = &new_fmap {
+            print!("{}", fmap.report());
+        }
+
+        // Check ROM size
+        let len = spi.len().map_err(|_| Status::DEVICE_ERROR)?;
+        println!("SPI ROM: {} MB", len / (1024 * 1024));
+        if len != new.len() {
+            println!("{} size invalid", self.file_name());
+            return Err(Status::DEVICE_ERROR);
+        }
+
+        // Back up current data before anything is erased
+        let mut backup = if !dry_run { Some(self.backup()?) } else { None };
+
+        // Read current data one sector at a time, keeping only the descriptor and FMAP
+        let mut head = Vec::new();
+        let mut fmap_addresses = Vec::new();
+        {
+            let mut buf = [0; 4096];
+            let mut print_mb = !0; // Invalid number to force first print
+            let mut address = 0;
+            while address < len {
+                let count = buf.len().min(len - address);
+                let chunk = &mut buf[..count];
+                spi_read_exact(spi, address, chunk).map_err(|err| {
+                    println!("\nSPI READ: {}", err);
+                    Status::DEVICE_ERROR
+                })?;
+
+                if let Some(backup) = &mut backup {
+                    backup.write(chunk).inspect_err(|err| {
+                        println!("\nSPI BACKUP: failed to write backup: {:?}", err);
+                    })?;
+                }
+
+                if address == 0 {
+                    head.extend_from_slice(chunk);
+                }
+                // coreboot aligns the FMAP, so the signature is never split between sectors
+                for (i, window) in chunk.windows(8).enumerate() {
+                    if window == b"__FMAP__" {
+                        fmap_addresses.push(address + i);
+                    }
+                }
+
+                address += count;
+
+                // Print output once per megabyte
+                let mb = address / (1024 * 1024);
+                if mb != print_mb {
+                    print!("\rSPI READ: {} MB", mb);
+                    print_mb = mb;
+                }
+            }
+            println!();
+        }
+
+        if let Some(backup) = backup {
+            match backup.finish() {
+                Ok(hash) => println!("SPI BACKUP: SHA-256 {}", hash),
+                Err(err) => {
+                    println!("SPI BACKUP: failed to write backup: {:?}", err);
+                    return Err(err);
+                }
+            }
+        }
+
+        // Grab old FMAP areas, if they exist, skipping signatures that are not a valid FMAP
+        let mut fmap = None;
+        for address in fmap_addresses {
+            let mut buf = vec![0; FMAP_MAX_SIZE.min(len - address)];
+            spi_read_exact(spi, address, &mut buf).map_err(|err| {
+                println!("SPI READ: {}", err);
+                Status::DEVICE_ERROR
+            })?;
+            fmap = fmap::new(&buf);
+            if fmap.is_some() {
+                break;
+            }
+        }
+        if let Some(fmap) = &fmap {
+            print!("{}", fmap.report());
+        }
+
+        // Grab old flash descriptor, if it exists
+        let old_descriptor = match Descriptor::from_head(&head, len) {
+            Ok(ok) => ok,
+            Err(err) => {
+                println!("IFD: failed to parse: {}", err);
+                return Err(Status::DEVICE_ERROR);
+            }
+        };
+
+        let mut partial = None;
+        let mut policy_report = String::new();
+        if self.restore {
+            // The backup already contains the GbE region and FMAP areas of this machine, so
+            // only make sure that it was taken from the same kind of firmware
+            let name = fmap.as_ref().map(|fmap| fmap.name.as_str());
+            let new_name = new_fmap.as_ref().map(|fmap| fmap.name.as_str());
+            if name != new_name {
+                println!(
+                    "restore.rom FMAP {:?} does not match current FMAP {:?}",
+                    new_name, name
+                );
+                return Err(Status::DEVICE_ERROR);
+            }
+            println!("Restoring SPI ROM from restore.rom");
+        } else {
+            // Keep locked and per-unit descriptor regions, like GbE, from the old firmware
+            match (&old_descriptor, descriptor::new(&new)) {
+                (Some(old_descriptor), Ok(Some(new_descriptor))) => {
+                    print!("{}", old_descriptor.report());
+                    match descriptor::apply(old_descriptor, spi, &new_descriptor, &mut new) {
+                        Ok(report) => print!("{}", report),
+                        Err(err) => {
+                            println!("IFD: refusing to flash: {}", err);
+                            return Err(Status::DEVICE_ERROR);
+                        }
+                    }
+                }
+                (None, Ok(None)) => (),
+                (_, Ok(_)) => {
+                    println!("IFD: refusing to flash: flash descriptor is added or removed");
+                    return Err(Status::DEVICE_ERROR);
+                }
+                (_, Err(err)) => {
+                    println!("IFD: failed to parse: {}", err);
+                    return Err(Status::DEVICE_ERROR);
+                }
+            }
+
+            // Copy, clear, or keep areas according to the policy
+            let mut policy_results = Vec::new();
+            if let (Some(fmap), Some(new_fmap)) = (&fmap, &new_fmap) {
+                let policy = fmap::load_policy()?;
+                policy_results = policy.apply(fmap, spi, new_fmap, &mut new).map_err(|err| {
+                    println!("FMAP policy: {}", err);
+                    Status::DEVICE_ERROR
+                })?;
+                policy_report = fmap::policy_report(&policy_results);
+                print!("{}", policy_report);
+            }
+
+            // Keep everything outside of the allowed areas for a partial update
+            if let Some(allowlist) = fmap::load_allowlist()? {
+                let (fmap, new_fmap) = match (&fmap, &new_fmap) {
+                    (Some(fmap), Some(new_fmap)) => (fmap, new_fmap),
+                    _ => {
+                        println!("PARTIAL: refusing to flash: FMAP not found");
+                        return Err(Status::DEVICE_ERROR);
+                    }
+                };
+                let result = allowlist
+                    .check_policy(&policy_results)
+                    .and_then(|()| allowlist.apply(fmap, spi, new_fmap, &mut new, 4096));
+                match result {
+                    Ok(ranges) => {
+                        for name in allowlist.names.iter() {
+                            println!("{}: allowed by partial update", name);
+                        }
+                        partial = Some(ranges);
+                    }
+                    Err(err) => {
+                        println!("PARTIAL: refusing to flash: {}", err);
+                        return Err(Status::DEVICE_ERROR);
+                    }
+                }
+            }
+        }
+
+        let mut plan = sector::read_plan(spi, &new, 4096, "SPI").map_err(|err| {
+            println!("\nSPI READ: {}", err);
+            Status::DEVICE_ERROR
+        })?;
+
+        // Every sector written by a partial update must be in an allowed area
+        if let Some(ranges) = &partial {
+            if let Some(sector) = plan.sectors.iter().find(|sector| {
+                !ranges.iter().any(|range| {
+                    range.start <= sector.address && sector.address + plan.sector_size <= range.end
+                })
+            }) {
+                println!(
+                    "PARTIAL: refusing to flash: sector {:#08X} is outside of the allowed areas",
+                    sector.address
+                );
+                return Err(Status::DEVICE_ERROR);
+            }
+            println!(
+                "PARTIAL: {} sectors differ, all in allowed areas",
+                plan.sectors.len()
+            );
+        }
+        let critical = plan.order_last(&critical_ranges(&new, new_fmap.as_ref()));
+
+        // Use block erases where the chip supports them
+        let descriptor_v2 = old_descriptor
+            .as_ref()
+            .is_some_and(|descriptor| descriptor.v2);
+        let cycles = SpiCycles::new(descriptor_v2);
+        if let Some(cycles) = &cycles {
+            match cycles.erase_sizes() {
+                Ok(sizes) => {
+                    let names: Vec<String> = sizes
+                        .iter()
+                        .map(|size| format!("{} KB", size / 1024))
+                        .collect();
+                    println!("SPI ERASE: {} supported", names.join(", "));
+                    if sizes.contains(&erase::BLOCK_SIZE) {
+                        plan.coalesce(erase::BLOCK_SIZE);
+                    }
+                }
+                Err(err) => println!("SPI ERASE: failed to read SFDP: {}", err),
+            }
+        }
+
+        // Report which areas will be changed
+        if let (Some(fmap), Some(new_fmap)) = (&fmap, &new_fmap) {
+            match fmap::diff(fmap, spi, new_fmap, &new, &plan) {
+                Ok(diffs) => {
+                    let report = fmap::report(fmap, new_fmap, &diffs);
+                    print!("{}", report);
+                    let log = format!("{}{}", policy_report, report);
+                    if let Err(err) = file::save(FMAPLOG, log.as_bytes()) {
+                        println!("Failed to write {}: {:?}", FMAPLOG, err);
+                    }
+                }
+                Err(err) => println!("FMAP diff: failed to read old firmware: {}", err),
+            }
+        }
+
+        // Refuse before writing anything if a locked range would be written
+        let allowed = Self::spi_allowed(hsfsts_ctl, &plan);
+
+        if dry_run {
+            print!("{}", plan.report("SPI", &SPI_TIMING));
+            return Ok(());
+        }
+
+        if !allowed {
+            println!("SPI LOCK: refusing to flash, nothing was written");
+            return Err(Status::WRITE_PROTECTED);
+        }
+
+        // Save the image and the write order before anything is erased, so an interrupted flash
+        // can be resumed without reading the old firmware again
+        let mut journal = match Journal::start(hash, &new, &plan.sectors, critical) {
+            Ok(ok) => ok,
+            Err(err) => {
+                println!("SPI JOURNAL: failed to save, refusing to flash: {:?}", err);
+                return Err(err);
+            }
+        };
+
+        Self::write_spi(spi, &new, &plan, cycles.as_ref(), &mut journal)?;
+        Self::finish_spi(spi, &new)
+    }
+
+    /// Resume an interrupted flash from the sector recorded in the journal
+    fn resume_spi(
+        spi: &mut SpiDev<'static, UefiMapper>,
+        hsfsts_ctl: HsfStsCtl,
+        mut journal: Journal,
+        dry_run: bool,
+    ) -> Result<()> {
+        println!(
+            "SPI JOURNAL: resuming interrupted flash at sector {} of {}",
+            journal.next,
+            journal.sectors.len()
+        );
+
+        let new = match journal.image() {
+            Ok(ok) => ok,
+            Err(err) => {
+                println!(
+                    "SPI JOURNAL: failed to load the image being written, flash a backup with restore.rom: {:?}",
+                    err
+                );
+                return Err(err);
+            }
+        };
+
+        let len = spi.len().map_err(|_| Status::DEVICE_ERROR)?;
+        if len != new.len() {
+            println!("SPI JOURNAL: image size does not match SPI ROM");
+            return Err(Status::DEVICE_ERROR);
+        }
+
+        // The saved image has areas of the old firmware copied in, so it is not signed and
+        // resuming must be confirmed at the machine
+        if !dry_run && !confirm_unsigned(JOURNALROM, &journal.target)? {
+            println!("SPI JOURNAL: not resuming, nothing was written");
+            return Err(Status::ACCESS_DENIED);
+        }
+
+        // The sector being written when interrupted is in an unknown state, so every remaining
+        // sector is erased, one sector at a time
+        let mut sectors = journal.sectors.clone();
+        for sector in sectors[journal.next..].iter_mut() {
+            sector.erase = true;
+        }
+        let plan = Plan {
+            sector_size: 4096,
+            len,
+            sectors,
+            block_size: 0,
+            blocks: Vec::new(),
+        };
+
+        let allowed = Self::spi_allowed(hsfsts_ctl, &plan);
+
+        if dry_run {
+            print!("{}", plan.report("SPI", &SPI_TIMING));
+            return Ok(());
+        }
+
+        if !allowed {
+            println!("SPI LOCK: refusing to flash");
+            return Err(Status::ABORTED);
+        }
+
+        Self::write_spi(spi, &new, &plan, None, &mut journal)?;
+        Self::finish_spi(spi, &new)
+    }
+
+    /// Check that no sector of the plan is in a locked range
+    fn spi_allowed(hsfsts_ctl: HsfStsCtl, plan: &Plan) -> bool {
+        match SpiProtection::new(hsfsts_ctl) {
+            Some(protection) => {
+                protection.print();
+                protection.check(plan)
+            }
+            None => {
+                println!("SPI LOCK: failed to read SPI controller protection");
+                true
+            }
+        }
+    }
+
+    /// Erase and write the sectors of the plan, starting from the journal, and recording
+    /// progress in it. Once anything is erased, failures return ABORTED so the updater is run
+    /// again on the next boot instead of booting a partially written ROM.
+    fn write_spi(
+        spi: &mut SpiDev<'static, UefiMapper>,
+        new: &[u8],
+        plan: &Plan,
+        cycles: Option<&SpiCycles>,
+        journal: &mut Journal,
+    ) -> Result<()> {
+        let len = new.len();
+        let start = journal.next;
+        let stopwatch = Stopwatch::start();
+        let mut failed_blocks = Vec::new();
+        let mut budget = SPI_RETRY_BUDGET;
+        let mut print_mb = !0; // Invalid number to force first print
+        for (i, sector) in plan.sectors.iter().enumerate().skip(start) {
+            // Record progress before the bootblock and descriptor are touched
+            if i > start && (i % JOURNAL_INTERVAL == 0 || i == journal.critical) {
+                journal.next = i;
+                if let Err(err) = journal.save() {
+                    println!("\nSPI JOURNAL: failed to save: {:?}", err);
+                }
+            }
+
+            // Erase the whole block at its first sector, falling back to sector erases
+            let mut sector = *sector;
+            if let (Some(block), Some(cycles)) = (plan.block(sector.address), cycles) {
+                if block == sector.address {
+                    if let Err(err) = cycles.erase_block(block) {
+                        println!("\nSPI ERASE: block {:#08X} failed: {}", block, err);
+                        failed_blocks.push(block);
+                    }
+                }
+                if !failed_blocks.contains(&block) {
+                    sector.erase = false;
+                }
+            }
+
+            let new_chunk = &new[sector.address..(sector.address + plan.sector_size).min(len)];
+            Self::flash_sector(spi, &sector, new_chunk, &mut budget)?;
+
+            // Print output once per megabyte
+            let mb = sector.address / (1024 * 1024);
+            if mb != print_mb {
+                print!("\rSPI WRITE: {} MB", mb);
+                print_mb = mb;
+            }
+        }
+        println!("\rSPI WRITE: {} MB", len / (1024 * 1024));
+        if budget < SPI_RETRY_BUDGET {
+            println!("SPI WRITE: {} retries used", SPI_RETRY_BUDGET - budget);
+        }
+        // Keep the measured time next to the estimates, to check them against real chips
+        let elapsed = stopwatch.elapsed_ms();
+        let timing = format!(
+            "SPI WRITE: took {}.{:03} seconds\n\
+             SPI WRITE: estimated {} seconds\n\
+             SPI WRITE: estimated {} seconds with only sector erases\n",
+            elapsed / 1000,
+            elapsed % 1000,
+            plan.estimate(&SPI_TIMING),
+            plan.estimate_sectors(&SPI_TIMING)
+        );
+        print!("{}", timing);
+        if let Err(err) = file::save(TIMINGLOG, timing.as_bytes()) {
+            println!("Failed to write {}: {:?}", TIMINGLOG, err);
+        }
+        Ok(())
+    }
+
+    /// Verify the whole ROM, then clear the journal and reset the option table
+    fn finish_spi(spi: &mut SpiDev<'static, UefiMapper>, new: &[u8]) -> Result<()> {
+        let len = new.len();
+
+        // Verify
+        {
+            let mut buf = [0; 4096];
+            let mut print_mb = !0; // Invalid number to force first print
+            let mut address = 0;
+            while address < len {
+                let count = buf.len().min(len - address);
+                let chunk = &mut buf[..count];
+                if let Err(err) = spi_read_exact(spi, address, chunk) {
+                    println!("\nSPI VERIFY: {}", err);
+                    return Err(Status::ABORTED);
+                }
+
+                let new_chunk = &new[address..address + count];
+                if let Some(i) = chunk.iter().zip(new_chunk.iter()).position(|(a, b)| a != b) {
+                    println!(
+                        "\nverification failed as {:#x}: {:#x} != {:#x}",
+                        address + i,
+                        chunk[i],
+                        new_chunk[i]
+                    );
+                    return Err(Status::ABORTED);
+                }
+
+                address += count;
+
+                let mb = address / (1024 * 1024);
+                if mb != print_mb {
+                    print!("\rSPI VERIFY: {} MB", mb);
+                    print_mb = mb;
+                }
+            }
+            println!();
+        }
+
+        if let Err(err) = Journal::clear() {
+            println!("SPI JOURNAL: failed to clear: {:?}", err);
+        }
+
+        // Have coreboot reset the option table to the defaults.
+        let mut cmos_options = cmos::CmosOptionTable::new();
+        unsafe {
+            cmos_options.invalidate_checksum();
+        }
+
+        Ok(())
+    }
+
     #[allow(dead_code)]
     fn spi_unlock() {
         if let Ok(mut ec) = EcFlash::new(true) {
@@ -203,7 +833,9 @@ impl Component for BiosComponent {
     }
 
     fn path(&self) -> &str {
-        if self.capsule {
+        if self.restore {
+            FIRMWARERESTORE
+        } else if self.capsule {
             FIRMWARECAP
         } else {
             FIRMWAREROM
@@ -218,283 +850,189 @@ impl Component for BiosComponent {
         &self.bios_version
     }
 
+    fn new_version(&self) -> String {
+        self.new_version.borrow().clone()
+    }
+
+    fn restore(&self) -> bool {
+        self.restore
+    }
+
     fn validate(&self) -> Result<bool> {
         let data = load(self.path())?;
 
-        if let Some((mut spi, _hsfsts_ctl)) = self.spi() {
+        // Backups are taken from this machine by the updater, so they are not signed, and flashing
+        // one is confirmed before anything is written
+        if !self.restore {
+            signature::verify(self.path(), &data)?;
+        }
+
+        if let Some((mut spi, hsfsts_ctl)) = self.spi() {
             // if hsfsts_ctl.contains(HsfStsCtl::FDOPSS) {
             //     println!("\nSPI currently locked, attempting to unlock");
             //     Self::spi_unlock();
             // }
 
-            let len = spi.len().map_err(|_| Status::DEVICE_ERROR)?;
-            Ok(data.len() == len)
-        } else if self.capsule {
-            Ok(true)
-        } else {
-            Ok(
-                data.len() == 8 * 1024 * 1024 ||
-                data.len() == 16 * 1024 * 1024 ||
-                data.len() == 32 * 1024 * 1024 ||
-                //TODO: rename firmware.rom to firmware.cap in these cases
-                find(H2OFFT).is_ok() || // H2OFFT capsule support
-                find(IFLASHV).is_ok() || // meer5 capsule support
-                find(UEFIFLASH).is_ok(), // meer4 capsule support
-            )
-        }
-    }
-
-    fn flash(&self) -> Result<()> {
-        if let Some((mut spi, _hsfsts_ctl)) = self.spi() {
-            // Read new data
-            let mut new;
+            if let Some(blocker) =
+                SpiProtection::new(hsfsts_ctl).and_then(|protection| protection.bios_blocker())
             {
-                let loading = "Loading";
-                print!("SPI FILE: {}", loading);
-                // TODO: Do not require two load operations
-                new = load(self.path())?;
-                for _c in loading.chars() {
-                    print!("\x08");
-                }
-                println!("{} MB", new.len() / (1024 * 1024));
+                println!("\nSPI LOCK: {}", blocker);
+                return Err(Status::WRITE_PROTECTED);
             }
 
-            // Grab new FMAP areas area, if they exist
-            let mut new_areas = BTreeMap::new();
-            {
-                let rom = Rom::new(&new);
-                if let Some(fmap) = rom.fmap() {
-                    let mut name = String::new();
-                    for &b in fmap.name.iter() {
-                        if b == 0 {
-                            break;
-                        }
-                        name.push(b as char);
-                    }
-
-                    println!("  {}", name);
-
-                    for i in 0..fmap.nareas {
-                        let area = fmap.area(i);
-
-                        let mut name = String::new();
-                        for &b in area.name.iter() {
-                            if b == 0 {
-                                break;
-                            }
-                            name.push(b as char);
-                        }
-
-                        println!("    {}: {}", i, name);
+            // Catch errors in the FMAP policy before flashing
+            fmap::load_policy()?;
 
-                        new_areas.insert(name, *area);
-                    }
-                }
+            if let Some(version) = cbfs::version(&data) {
+                *self.new_version.borrow_mut() = version;
             }
 
-            // Check ROM size
             let len = spi.len().map_err(|_| Status::DEVICE_ERROR)?;
-            println!("SPI ROM: {} MB", len / (1024 * 1024));
-            if len != new.len() {
-                println!("firmware.rom size invalid");
-                return Err(Status::DEVICE_ERROR);
-            }
-
-            // Read current data
-            let mut data;
-            {
-                data = Vec::with_capacity(len);
-                let mut print_mb = !0; // Invalid number to force first print
-                while data.len() < len {
-                    let mut buf = [0; 4096];
-                    let read = spi
-                        .read(data.len(), &mut buf)
-                        .map_err(|_| Status::DEVICE_ERROR)?;
-                    data.extend_from_slice(&buf[..read]);
-
-                    // Print output once per megabyte
-                    let mb = data.len() / (1024 * 1024);
-                    if mb != print_mb {
-                        print!("\rSPI READ: {} MB", mb);
-                        print_mb = mb;
-                    }
-                }
-                println!();
-            }
-
-            // Copy GBE region, if it exists
-            match copy_region(intelflash::RegionKind::Ethernet, &data, &mut new) {
-                Ok(true) => println!("Ethernet: copied region from old firmware to new firmare"),
-                Ok(false) => (),
-                Err(err) => {
-                    println!("Ethernet: failed to copy: {}", err);
-                    return Err(Status::DEVICE_ERROR);
-                }
-            }
-
-            // Grab old FMAP areas, if they exist
-            let mut areas = BTreeMap::new();
-            {
-                let rom = Rom::new(&data);
-                if let Some(fmap) = rom.fmap() {
-                    let mut name = String::new();
-                    for &b in fmap.name.iter() {
-                        if b == 0 {
-                            break;
-                        }
-                        name.push(b as char);
-                    }
-
-                    println!("  {}", name);
-
-                    for i in 0..fmap.nareas {
-                        let area = fmap.area(i);
-
-                        let mut name = String::new();
-                        for &b in area.name.iter() {
-                            if b == 0 {
-                                break;
-                            }
-                            name.push(b as char);
-                        }
-
-                        println!("    {}: {}", i, name);
-
-                        areas.insert(name, *area);
-                    }
-                }
+            if data.len() != len {
+                return Ok(false);
             }
 
-            // Copy old areas to new areas
-            let area_names = ["SMMSTORE".to_string()];
-            for area_name in &area_names {
-                if let Some(new_area) = new_areas.get(area_name) {
-                    let new_offset = new_area.offset as usize;
-                    let new_size = new_area.size as usize;
+            // Firmware for another model cannot be recovered without an external programmer
+            let valid = match cbfs::board(&data) {
+                Some(board) if board == self.system_version => true,
+                Some(board) => {
                     println!(
-                        "{}: found in new firmware: offset {:#X}, size {} KB",
-                        area_name,
-                        new_offset,
-                        new_size / 1024
+                        "\n{} is for {}, but this system is {}",
+                        self.file_name(),
+                        board,
+                        self.system_version
                     );
-                    let new_slice = new
-                        .get_mut(new_offset..new_offset + new_size)
-                        .ok_or(Status::DEVICE_ERROR)?;
-
-                    if let Some(area) = areas.get(area_name) {
-                        let offset = area.offset as usize;
-                        let size = area.size as usize;
-                        println!(
-                            "{}: found in old firmware: offset {:#X}, size {} KB",
-                            area_name,
-                            new_offset,
-                            new_size / 1024
-                        );
-                        let slice = data
-                            .get(offset..offset + size)
-                            .ok_or(Status::DEVICE_ERROR)?;
-
-                        if slice.len() == new_slice.len() {
-                            new_slice.copy_from_slice(slice);
-
-                            println!("{}: copied from old firmware to new firmware", area_name);
-                        } else {
-                            println!(
-                                "{}: old firmware size {} does not match new firmware size {}, not copying",
-                                area_name,
-                                slice.len(),
-                                new_slice.len()
-                            );
-                        }
+                    false
+                }
+                None => {
+                    let reason = if cbfs::compressed(&data, "config") {
+                        "coreboot config is compressed"
                     } else {
-                        println!(
-                            "{}: found in new firmware, but not found in old firmware",
-                            area_name
-                        );
+                        "board not found in image"
+                    };
+                    if find(ALLOWUNKNOWNBOARD).is_err() {
+                        println!("\n{}: {}, unable to verify model", self.file_name(), reason);
+                        // Reported as an error, so no other component is flashed either
+                        return Err(Status::INCOMPATIBLE_VERSION);
                     }
-                } else if areas.contains_key(area_name) {
                     println!(
-                        "{}: found in old firmware, but not found in new firmware",
-                        area_name
+                        "\n{}: {}, flashing anyway because of allow-unknown-board",
+                        self.file_name(),
+                        reason
                     );
+                    true
                 }
+            };
+            if valid {
+                *self.image.borrow_mut() = Some(data);
             }
-
-            // Erase and write
-            {
-                let erase_byte = 0xFF;
-                let erase_size = 4096;
-                let mut i = 0;
-                let mut print_mb = !0; // Invalid number to force first print
-                for (chunk, new_chunk) in data.chunks(erase_size).zip(new.chunks(erase_size)) {
-                    // Data matches, meaning sector can be skipped
-                    let mut matching = true;
-                    // Data is erased, meaning sector can be erased instead of written
-                    let mut erased = true;
-                    for (&byte, &new_byte) in chunk.iter().zip(new_chunk.iter()) {
-                        if new_byte != byte {
-                            matching = false;
-                        }
-                        if new_byte != erase_byte {
-                            erased = false;
-                        }
-                    }
-
-                    if !matching {
-                        spi.erase(i).unwrap();
-                        if !erased {
-                            spi.write(i, new_chunk).unwrap();
-                        }
-                    }
-
-                    i += chunk.len();
-
-                    // Print output once per megabyte
-                    let mb = i / (1024 * 1024);
-                    if mb != print_mb {
-                        print!("\rSPI WRITE: {} MB", mb);
-                        print_mb = mb;
-                    }
+            Ok(valid)
+        } else if self.restore {
+            println!("\nrestore.rom requires SPI access");
+            Err(Status::UNSUPPORTED)
+        } else if self.capsule {
+            // Standard capsules for resources in the ESRT are submitted directly, anything else
+            // is left to the vendor script
+            match Capsule::parse(&data) {
+                Ok(capsule) => {
+                    capsule.print();
+                    self.native_capsule.set(capsule.supported());
                 }
-                println!();
+                Err(err) => println!("\n{}: {}", self.file_name(), err),
+            }
+            if self.native_capsule.get() {
+                *self.image.borrow_mut() = Some(data);
             }
+            Ok(true)
+        } else {
+            Ok(
+                data.len() == 8 * 1024 * 1024 ||
+                data.len() == 16 * 1024 * 1024 ||
+                data.len() == 32 * 1024 * 1024 ||
+                //TODO: rename firmware.rom to firmware.cap in these cases
+                find(H2OFFT).is_ok() || // H2OFFT capsule support
+                find(IFLASHV).is_ok() || // meer5 capsule support
+                find(UEFIFLASH).is_ok(), // meer4 capsule support
+            )
+        }
+    }
 
-            // Verify
-            {
-                data.clear();
-                let mut print_mb = !0; // Invalid number to force first print
-                while data.len() < len {
-                    let mut address = data.len();
-
-                    let mut buf = [0; 4096];
-                    let read = spi.read(address, &mut buf).unwrap();
-                    data.extend_from_slice(&buf[..read]);
-
-                    while address < data.len() {
-                        if data[address] != new[address] {
-                            println!(
-                                "\nverification failed as {:#x}: {:#x} != {:#x}",
-                                address, data[address], new[address]
-                            );
-                            return Err(Status::DEVICE_ERROR);
-                        }
-                        address += 1;
-                    }
+    fn dry_run(&self) -> Result<()> {
+        if let Some((mut spi, hsfsts_ctl)) = self.spi() {
+            self.flash_spi(&mut spi, hsfsts_ctl, true)
+        } else if self.native_capsule.get() {
+            println!("{}: capsule submitted with UpdateCapsule", self.name());
+            Ok(())
+        } else {
+            println!(
+                "{}: flashed by vendor tools, no plan available",
+                self.name()
+            );
+            Ok(())
+        }
+    }
 
-                    let mb = data.len() / (1024 * 1024);
-                    if mb != print_mb {
-                        print!("\rSPI VERIFY: {} MB", mb);
-                        print_mb = mb;
-                    }
-                }
-                println!();
+    fn dump(&self) -> Result<()> {
+        let mut spi = match self.spi() {
+            Some((spi, _hsfsts_ctl)) => spi,
+            None => {
+                // Reported as a failure, so a dump that is missing the BIOS is not mistaken for
+                // a complete one
+                println!("{}: SPI not readable, cannot dump", self.name());
+                return Err(Status::UNSUPPORTED);
             }
-
-            // Have coreboot reset the option table to the defaults.
-            let mut cmos_options = cmos::CmosOptionTable::new();
-            unsafe {
-                cmos_options.invalidate_checksum();
+        };
+
+        let len = spi.len().map_err(|_| Status::DEVICE_ERROR)?;
+        println!("SPI ROM: {} MB", len / (1024 * 1024));
+
+        let path = format!(
+            "{}\\{}-bios-{}.rom",
+            DUMPDIR,
+            file::sanitize(&self.system_version),
+            file::timestamp()
+        );
+        println!("SPI DUMP: {}", path);
+        file::create_dir(DUMPDIR)?;
+        let mut writer = file::VerifiedWriter::create(&path)?;
+
+        let mut buf = [0; 4096];
+        let mut print_mb = !0; // Invalid number to force first print
+        let mut address = 0;
+        while address < len {
+            let count = buf.len().min(len - address);
+            let chunk = &mut buf[..count];
+            spi_read_exact(&mut spi, address, chunk).map_err(|err| {
+                println!("\nSPI READ: {}", err);
+                Status::DEVICE_ERROR
+            })?;
+            writer.write(chunk)?;
+
+            address += count;
+
+            // Print output once per megabyte
+            let mb = address / (1024 * 1024);
+            if mb != print_mb {
+                print!("\rSPI READ: {} MB", mb);
+                print_mb = mb;
             }
+        }
+        println!();
+
+        let hash = writer.finish()?;
+        println!("SPI DUMP: SHA-256 {}", hash);
+        Ok(())
+    }
+
+    fn flash(&self) -> Result<()> {
+        if let Some((mut spi, hsfsts_ctl)) = self.spi() {
+            self.flash_spi(&mut spi, hsfsts_ctl, false)?;
+        } else if self.native_capsule.get() {
+            let data = match self.image.take() {
+                Some(image) => image,
+                None => load(self.path())?,
+            };
+            capsule::update(&data)?;
         } else {
             find(FIRMWARENSH)?;
 
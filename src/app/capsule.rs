@@ -0,0 +1,262 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use alloc::string::String;
+use core::ptr;
+use core::sync::atomic::{AtomicU32, Ordering};
+use std::prelude::*;
+use std::uefi::reset::ResetType;
+
+use super::esrt;
+
+const FMP_CAPSULE_GUID: Guid = Guid(
+    0x6dcb_d5ed,
+    0xe82d,
+    0x4c44,
+    [0xbd, 0xa1, 0x71, 0x94, 0x19, 0x9a, 0xd9, 0x2a],
+);
+
+const CAPSULE_FLAGS_PERSIST_ACROSS_RESET: u32 = 0x0001_0000;
+const CAPSULE_FLAGS_POPULATE_SYSTEM_TABLE: u32 = 0x0002_0000;
+const CAPSULE_FLAGS_INITIATE_RESET: u32 = 0x0004_0000;
+
+// Values of EFI_RESET_TYPE
+const RESET_COLD: u32 = 0;
+const RESET_WARM: u32 = 1;
+const RESET_NONE: u32 = u32::MAX;
+
+/// Reset required to process a capsule submitted to persist across reset
+static PENDING_RESET: AtomicU32 = AtomicU32::new(RESET_NONE);
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        data.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+fn read_guid(data: &[u8], offset: usize) -> Option<Guid> {
+    let bytes = data.get(offset..offset + 16)?;
+    Some(Guid(
+        read_u32(bytes, 0)?,
+        read_u16(bytes, 4)?,
+        read_u16(bytes, 6)?,
+        bytes[8..16].try_into().ok()?,
+    ))
+}
+
+/// A payload of a Firmware Management Protocol capsule
+pub struct FmpImage {
+    pub type_id: Guid,
+    pub index: u8,
+    pub size: u32,
+}
+
+/// Headers of a UEFI capsule
+pub struct Capsule {
+    pub guid: Guid,
+    pub header_size: u32,
+    pub flags: u32,
+    pub image_size: u32,
+    /// Payloads, if this is an FMP capsule
+    pub fmp_images: Vec<FmpImage>,
+}
+
+impl Capsule {
+    /// Parse the EFI_CAPSULE_HEADER, and the FMP capsule headers if there are any
+    pub fn parse(data: &[u8]) -> core::result::Result<Self, String> {
+        let invalid = || "capsule header is truncated".to_string();
+
+        let guid = read_guid(data, 0).ok_or_else(invalid)?;
+        let header_size = read_u32(data, 16).ok_or_else(invalid)?;
+        let flags = read_u32(data, 20).ok_or_else(invalid)?;
+        let image_size = read_u32(data, 24).ok_or_else(invalid)?;
+        if header_size < 28 || header_size > image_size {
+            return Err(format!(
+                "capsule header size {} is invalid for image size {}",
+                header_size, image_size
+            ));
+        }
+        if image_size as usize != data.len() {
+            return Err(format!(
+                "capsule image size {} does not match file size {}",
+                image_size,
+                data.len()
+            ));
+        }
+
+        let mut fmp_images = Vec::new();
+        if guid == FMP_CAPSULE_GUID {
+            let fmp = &data[header_size as usize..];
+            let version = read_u32(fmp, 0).ok_or_else(invalid)?;
+            if version != 1 {
+                return Err(format!("FMP capsule version {} is not supported", version));
+            }
+            let drivers = read_u16(fmp, 4).ok_or_else(invalid)? as usize;
+            let payloads = read_u16(fmp, 6).ok_or_else(invalid)? as usize;
+            for i in drivers..drivers + payloads {
+                let offset = read_u64(fmp, 8 + i * 8).ok_or_else(invalid)? as usize;
+                let item = fmp.get(offset..).ok_or_else(invalid)?;
+                fmp_images.push(FmpImage {
+                    type_id: read_guid(item, 4).ok_or_else(invalid)?,
+                    index: *item.get(20).ok_or_else(invalid)?,
+                    size: read_u32(item, 24).ok_or_else(invalid)?,
+                });
+            }
+            if fmp_images.is_empty() {
+                return Err("FMP capsule has no payloads".to_string());
+            }
+        }
+
+        Ok(Self {
+            guid,
+            header_size,
+            flags,
+            image_size,
+            fmp_images,
+        })
+    }
+
+    /// GUIDs of the firmware resources this capsule updates
+    fn classes(&self) -> Vec<Guid> {
+        if self.fmp_images.is_empty() {
+            vec![self.guid]
+        } else {
+            self.fmp_images.iter().map(|image| image.type_id).collect()
+        }
+    }
+
+    pub fn print(&self) {
+        println!(
+            "CAPSULE: {:?}, header {} bytes, flags {:#X}, {} KB",
+            self.guid,
+            self.header_size,
+            self.flags,
+            self.image_size / 1024
+        );
+        for image in self.fmp_images.iter() {
+            println!(
+                "CAPSULE: FMP payload {:?} index {}, {} KB",
+                image.type_id,
+                image.index,
+                image.size / 1024
+            );
+        }
+    }
+
+    /// Check that every resource the capsule updates is listed in the ESRT, meaning firmware
+    /// will process it through UpdateCapsule
+    pub fn supported(&self) -> bool {
+        let mut supported = true;
+        for class in self.classes() {
+            match esrt::find(&class) {
+                Some(entry) => {
+                    let version = entry.fw_version;
+                    println!("CAPSULE: {:?} found in ESRT, version {:#X}", class, version);
+                }
+                None => {
+                    println!("CAPSULE: {:?} not found in ESRT", class);
+                    supported = false;
+                }
+            }
+        }
+        supported
+    }
+}
+
+/// Submit a capsule with UpdateCapsule, persisting it across reset if the firmware supports it
+pub fn update(data: &[u8]) -> Result<()> {
+    let capsule = Capsule::parse(data).map_err(|err| {
+        println!("CAPSULE: {}", err);
+        Status::LOAD_ERROR
+    })?;
+
+    let uefi = std::system_table();
+
+    // Firmware reads the capsule from memory, possibly after reset, so it is copied to an aligned
+    // buffer that is never freed
+    let buffer: &'static mut [u64] = vec![0u64; data.len().div_ceil(8)].leak();
+    let capsule_ptr = buffer.as_mut_ptr() as *mut u8;
+    unsafe { ptr::copy_nonoverlapping(data.as_ptr(), capsule_ptr, data.len()) };
+    let set_flags =
+        |flags: u32| unsafe { (capsule_ptr.add(20) as *mut u32).write_unaligned(flags) };
+
+    // The capsule services are called through the typed runtime services table, with the header
+    // pointer cast to the header type it declares. The reset type is read as a raw u32, since
+    // firmware may return values that are not in ResetType.
+    let headers = [capsule_ptr.cast_const().cast()];
+    let query = |flags: u32| -> Result<(u64, u32)> {
+        set_flags(flags);
+        let mut max_size = 0;
+        let mut reset_type = RESET_COLD;
+        Result::from((uefi.RuntimeServices.QueryCapsuleCapabilities)(
+            headers.as_ptr(),
+            headers.len(),
+            &mut max_size,
+            ptr::addr_of_mut!(reset_type).cast(),
+        ))?;
+        Ok((max_size, reset_type))
+    };
+
+    // Resetting is left to the updater, so it can finish other components first
+    let base_flags = capsule.flags & !CAPSULE_FLAGS_INITIATE_RESET;
+    let persist_flags = base_flags | CAPSULE_FLAGS_PERSIST_ACROSS_RESET;
+    let (flags, reset_type) = match query(persist_flags) {
+        Ok((max_size, reset_type)) if max_size >= data.len() as u64 => {
+            println!(
+                "CAPSULE: processed after reset, max size {} KB",
+                max_size / 1024
+            );
+            (persist_flags, Some(reset_type))
+        }
+        result => {
+            if let Err(err) = result {
+                println!("CAPSULE: persist across reset not supported: {:?}", err);
+            }
+            // Without persisting, the system table cannot be populated
+            let flags = base_flags
+                & !(CAPSULE_FLAGS_PERSIST_ACROSS_RESET | CAPSULE_FLAGS_POPULATE_SYSTEM_TABLE);
+            query(flags)?;
+            println!("CAPSULE: processed immediately");
+            (flags, None)
+        }
+    };
+    set_flags(flags);
+
+    // One block descriptor for the whole capsule, then a terminator
+    let descriptors: &'static mut [u64] = vec![data.len() as u64, capsule_ptr as u64, 0, 0].leak();
+    Result::from((uefi.RuntimeServices.UpdateCapsule)(
+        headers.as_ptr(),
+        headers.len(),
+        descriptors.as_ptr() as _,
+    ))?;
+
+    if let Some(reset_type) = reset_type {
+        PENDING_RESET.store(reset_type, Ordering::SeqCst);
+    }
+
+    if let Err(err) = esrt::save_pending(&capsule.classes()) {
+        println!("CAPSULE: failed to record pending capsule: {:?}", err);
+    }
+
+    Ok(())
+}
+
+/// Reset needed for a capsule submitted by update to be processed
+pub fn pending_reset() -> Option<ResetType> {
+    match PENDING_RESET.load(Ordering::SeqCst) {
+        RESET_NONE => None,
+        RESET_WARM => Some(ResetType::Warm),
+        _ => Some(ResetType::Cold),
+    }
+}
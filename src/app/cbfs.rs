@@ -0,0 +1,166 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use alloc::string::String;
+use core::str;
+use std::prelude::*;
+
+use super::fmap;
+
+const CBFS_ALIGN: usize = 64;
+const CBFS_MAGIC: &[u8; 8] = b"LARCHIVE";
+const CBFS_HEADER_SIZE: usize = 24;
+const CBFS_ATTR_COMPRESSION: u32 = 0x4243_5a4c;
+const CBFS_COMPRESS_NONE: u32 = 0;
+
+fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+pub struct CbfsFile<'a> {
+    pub name: String,
+    pub compression: u32,
+    pub data: &'a [u8],
+}
+
+/// Parse the file header at offset, returning the file and the offset after it
+fn parse(data: &[u8], offset: usize) -> Option<(CbfsFile<'_>, usize)> {
+    if data.get(offset..offset + CBFS_MAGIC.len())? != CBFS_MAGIC {
+        return None;
+    }
+
+    let len = be32(data, offset + 8)? as usize;
+    let attributes_offset = be32(data, offset + 16)? as usize;
+    let data_offset = be32(data, offset + 20)? as usize;
+    if data_offset < CBFS_HEADER_SIZE {
+        return None;
+    }
+
+    let header = data.get(offset..offset + data_offset)?;
+    let name_end = if attributes_offset >= CBFS_HEADER_SIZE {
+        attributes_offset
+    } else {
+        data_offset
+    };
+    let mut name = String::new();
+    for &b in header.get(CBFS_HEADER_SIZE..name_end)? {
+        if b == 0 {
+            break;
+        }
+        name.push(b as char);
+    }
+
+    let mut compression = CBFS_COMPRESS_NONE;
+    if attributes_offset >= CBFS_HEADER_SIZE {
+        let mut attribute = attributes_offset;
+        while attribute + 8 <= data_offset {
+            let tag = be32(header, attribute)?;
+            let size = be32(header, attribute + 4)? as usize;
+            if tag == CBFS_ATTR_COMPRESSION {
+                compression = be32(header, attribute + 8)?;
+            }
+            if size < 8 {
+                break;
+            }
+            attribute += size;
+        }
+    }
+
+    let start = offset + data_offset;
+    let file = CbfsFile {
+        name,
+        compression,
+        data: data.get(start..start + len)?,
+    };
+    Some((file, (start + len).next_multiple_of(CBFS_ALIGN)))
+}
+
+/// List the files in the COREBOOT region, or the whole image if there is no FMAP
+pub fn files(data: &[u8]) -> Vec<CbfsFile<'_>> {
+    let range = fmap::new(data)
+        .and_then(|fmap| fmap.areas.get("COREBOOT").map(|area| area.range()))
+        .unwrap_or(0..data.len());
+    let cbfs = match data.get(range) {
+        Some(some) => some,
+        None => return Vec::new(),
+    };
+
+    let mut files = Vec::new();
+    let mut offset = 0;
+    while offset + CBFS_HEADER_SIZE <= cbfs.len() {
+        match parse(cbfs, offset) {
+            Some((file, next)) => {
+                files.push(file);
+                offset = next;
+            }
+            None => offset += CBFS_ALIGN,
+        }
+    }
+    files
+}
+
+/// Find an uncompressed file by name
+pub fn file<'a>(data: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    files(data)
+        .into_iter()
+        .find(|file| file.name == name && file.compression == CBFS_COMPRESS_NONE)
+        .map(|file| file.data)
+}
+
+/// Check if a file exists, but is compressed, so it cannot be read with file
+pub fn compressed(data: &[u8], name: &str) -> bool {
+    files(data)
+        .into_iter()
+        .any(|file| file.name == name && file.compression != CBFS_COMPRESS_NONE)
+}
+
+/// Look up an option in the coreboot config stored in CBFS
+pub fn config<'a>(data: &'a [u8], key: &str) -> Option<&'a str> {
+    let config = str::from_utf8(file(data, "config")?).ok()?;
+    for line in config.lines() {
+        if let Some(value) = line.strip_prefix(key).and_then(|s| s.strip_prefix('=')) {
+            return Some(value.trim_matches('"'));
+        }
+    }
+    None
+}
+
+/// Get the board name of a coreboot image, such as `gaze16-3060`
+pub fn board(data: &[u8]) -> Option<String> {
+    if let Some(version) = config(data, "CONFIG_MAINBOARD_VERSION") {
+        return Some(version.to_string());
+    }
+
+    // Boards are selected with CONFIG_BOARD_<VENDOR>_<MODEL>=y
+    let config = str::from_utf8(file(data, "config")?).ok()?;
+    for line in config.lines() {
+        if let Some(board) = line
+            .strip_prefix("CONFIG_BOARD_SYSTEM76_")
+            .and_then(|s| s.strip_suffix("=y"))
+        {
+            return Some(board.to_ascii_lowercase().replace('_', "-"));
+        }
+    }
+    None
+}
+
+/// Get the version of a coreboot image, from RO_FRID or the config stored in CBFS
+pub fn version(data: &[u8]) -> Option<String> {
+    if let Some(area) = fmap::new(data).and_then(|fmap| fmap.areas.get("RO_FRID").copied()) {
+        let mut version = String::new();
+        for &b in data.get(area.range())? {
+            if b == 0 {
+                break;
+            }
+            version.push(b as char);
+        }
+        let version = version.trim();
+        if !version.is_empty() {
+            return Some(version.to_string());
+        }
+    }
+
+    config(data, "CONFIG_LOCALVERSION")
+        .filter(|version| !version.is_empty())
+        .map(|version| version.to_string())
+}
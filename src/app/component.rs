@@ -7,6 +7,14 @@ pub trait Component {
     fn path(&self) -> &str;
     fn model(&self) -> &str;
     fn version(&self) -> &str;
+    /// Version of the image that will be flashed, available after validate
+    fn new_version(&self) -> String;
+    /// Flashing a backup of this machine, which is expected to be older
+    fn restore(&self) -> bool;
     fn validate(&self) -> Result<bool>;
+    /// Print what flash would do, without erasing or writing anything
+    fn dry_run(&self) -> Result<()>;
+    /// Read the current firmware to a timestamped file, without erasing or writing anything
+    fn dump(&self) -> Result<()>;
     fn flash(&self) -> Result<()>;
 }
@@ -0,0 +1,14 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use alloc::string::String;
+
+pub use system76_firmware_update_logic::descriptor::*;
+
+/// Parse the flash descriptor, if the image has one
+pub fn new(data: &[u8]) -> core::result::Result<Option<Descriptor>, String> {
+    let descriptor = Descriptor::from_head(data, data.len())?;
+    if descriptor.is_some() {
+        intelflash::Rom::new(data)?;
+    }
+    Ok(descriptor)
+}
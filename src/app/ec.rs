@@ -1,8 +1,9 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
-use core::cell::Cell;
+use core::cell::{Cell, OnceCell, RefCell};
 use core::ptr;
 use core::str;
+use core::sync::atomic::{AtomicBool, Ordering};
 use ecflash::{Ec, EcFile, EcFlash};
 use ectool::{
     Access, AccessLpcDirect, Firmware, SecurityState, Spi, SpiRom, SpiTarget, Timeout, timeout,
@@ -14,9 +15,30 @@ use std::{
     ffi::wstr,
     fs::{find, load},
 };
+use system76_firmware_update_logic::ec_flash;
 
+use super::alias::{self, Hardware};
+use super::sector::{Plan, RomReader, RomWriter, Timing};
 use super::{
-    Component, EC2ROM, ECROM, ECTAG, FIRMWAREDIR, FIRMWARENSH, pci_read, shell, sideband::Sideband,
+    Component, DUMPDIR, EC2ROM, ECBACKUPPREVIOUS, ECROM, ECTAG, FIRMWAREDIR, FIRMWARENSH, file,
+    pci_read, shell, sideband::Sideband, signature,
+};
+
+/// Size of the EC ROM read by dump when the flash size could not be detected
+const EC_DUMP_SIZE: usize = 128 * 1024;
+
+/// Legacy EC left running from the scratch ROM by dump, which is reset after every dump is written
+static SCRATCH_RESET: AtomicBool = AtomicBool::new(false);
+
+/// SPI command to read the manufacturer and device ID
+const SPI_READ_JEDEC_ID: u8 = 0x9F;
+
+static EC_TIMING: Timing = Timing {
+    erase_us: 100_000,
+    // EC flash is always erased by sector
+    block_erase_us: 0,
+    write_us: 200_000,
+    read_us_per_mb: 20_000_000,
 };
 
 pub struct UefiTimeout {
@@ -60,23 +82,7 @@ impl EcKind {
     pub unsafe fn new(primary: bool) -> Self {
         // Special case for pang12, pang13, pang14, and pang15
         {
-            let mut system_version = String::new();
-
-            for table in crate::dmi::dmi() {
-                match table.header.kind {
-                    1 => {
-                        if let Ok(info) = dmi::SystemInfo::from_bytes(&table.data) {
-                            let index = info.version;
-                            if index > 0 {
-                                if let Some(value) = table.strings.get((index - 1) as usize) {
-                                    system_version = value.trim().to_string();
-                                }
-                            }
-                        }
-                    }
-                    _ => {}
-                }
-            }
+            let system_version = crate::dmi::system_version();
 
             if system_version == "pang12"
                 || system_version == "pang13"
@@ -192,6 +198,36 @@ impl EcKind {
         String::new()
     }
 
+    /// Size of the main or backup EC flash, if it can be detected. The EC is probed in a new
+    /// session, as the components sharing it only borrow it.
+    unsafe fn flash_size(&self, primary: bool, target: SpiTarget) -> Option<usize> {
+        match self {
+            // Vendor firmware has no command to read the flash size
+            EcKind::Pang(_pmc, _system_version) => None,
+            EcKind::System76(_ec, _pmc) => {
+                let access = unsafe { AccessLpcDirect::new(UefiTimeout::new(100_000)).ok()? };
+                let mut ec = unsafe { ectool::Ec::new(access).ok()? };
+                let mut spi_bus = unsafe { ec.spi(target, false).ok()? };
+                unsafe { chip_size(&mut spi_bus) }
+            }
+            // Legacy ECs have no backup flash
+            EcKind::Legacy(_ec) if matches!(target, SpiTarget::Backup) => None,
+            EcKind::Legacy(_ec) => {
+                let mut ec = EcFlash::new(primary).ok()?;
+                let size = ec.size();
+                println!("EC flash: {} KB", size / 1024);
+                Some(size).filter(|size| *size > 0)
+            }
+            EcKind::Unknown => None,
+        }
+    }
+
+    /// Legacy EC firmware is laid out for the size the EC reports, so images must fill the
+    /// flash. System76 EC images can be smaller than the chip, which is written from the start.
+    fn fills_flash(&self) -> bool {
+        matches!(self, EcKind::Legacy(_ec))
+    }
+
     fn firmware_model(&self, data: Vec<u8>) -> String {
         if let Some(firmware) = Firmware::new(&data) {
             if let Ok(string) = str::from_utf8(firmware.board) {
@@ -203,6 +239,24 @@ impl EcKind {
             EcFile::new(data).project()
         }
     }
+
+    fn firmware_version(&self, data: &[u8]) -> String {
+        match self {
+            // Pang firmware does not have a version in the image
+            EcKind::Pang(_pmc, _system_version) => String::new(),
+            _ => {
+                if let Some(firmware) = Firmware::new(data) {
+                    if let Ok(string) = str::from_utf8(firmware.version) {
+                        string.to_string()
+                    } else {
+                        String::new()
+                    }
+                } else {
+                    EcFile::new(data.to_vec()).version()
+                }
+            }
+        }
+    }
 }
 
 pub struct EcComponent {
@@ -210,6 +264,9 @@ pub struct EcComponent {
     ec: EcKind,
     model: String,
     version: String,
+    /// Size of the main EC flash, probed when an image for this EC is checked
+    flash_size: OnceCell<Option<usize>>,
+    new_version: RefCell<String>,
 }
 
 impl EcComponent {
@@ -224,124 +281,52 @@ impl EcComponent {
                 master,
                 model,
                 version,
+                flash_size: OnceCell::new(),
+                new_version: RefCell::new(String::new()),
             }
         }
     }
 
+    /// Size of the main EC flash, only probed for an EC that has an image
+    fn flash_size(&self) -> Option<usize> {
+        *self
+            .flash_size
+            .get_or_init(|| unsafe { self.ec.flash_size(self.master, SpiTarget::Main) })
+    }
+
     pub fn validate_data(&self, data: Vec<u8>) -> bool {
+        // Images must fit the flash, and fill it on ECs that use all of it
+        if let Some(flash_size) = self.flash_size() {
+            let fill = self.ec.fills_flash();
+            if let Err(err) = ec_flash::check_image_size(data.len(), Some(flash_size), fill) {
+                println!("{}: {}", self.name(), err);
+                return false;
+            }
+        }
+
         // Special case for pang12, pang13, pang14, and pang15
         match &self.ec {
             EcKind::Pang(_pmc, _system_version) => {
-                // XXX: Get flash size programatically?
+                // Flash size cannot be read from vendor firmware, so only the chip sizes used
+                // by these models are accepted
                 return (data.len() == 128 * 1024 || data.len() == 256 * 1024)
                     && &data[0x50..=0x05F] == b"ITE EC-V14.6   \0";
             }
             _ => (),
         }
 
-        let normalize_model = |model: &str| -> String {
-            match model {
-                "L140CU" => "system76/lemp9".to_string(),
-                "L140MU" => "system76/lemp10".to_string(),
-                "L140PU" => "system76/lemp11".to_string(),
-                "L140AU" => "system76/lemp12".to_string(),
-                "L2x0TU" => {
-                    // If the EC version starts with 1.07. then this is the original keyboard
-                    if self.version.starts_with("1.07.") {
-                        "system76/lemp13".to_string()
-                    } else {
-                        "system76/lemp13-b".to_string()
-                    }
-                }
-                "N130ZU" => "system76/galp3-c".to_string(),
-                "N140CU" => "system76/galp4".to_string(),
-                "N150ZU" => "system76/darp5".to_string(),
-                "N150CU" => "system76/darp6".to_string(),
-                "NH50DB" | "NH5xDC" => "system76/gaze15".to_string(),
-                "NH5xHX" => "system76/gaze16-3050".to_string(),
-                "NH5_7HPQ" => {
-                    // If the builtin ethernet at 00:1f.6 is present, this is a -b variant
-                    if pci_read(0x00, 0x1f, 0x6, 0x00).unwrap() == 0x15fa8086 {
-                        "system76/gaze16-3060-b".to_string()
-                    } else {
-                        "system76/gaze16-3060".to_string()
-                    }
-                }
-                "NPxxPNJ_K" => "system76/gaze17-3050".to_string(),
-                "NPxxPNP" => {
-                    // If the builtin ethernet at 00:1f.6 is present, this is a -b variant
-                    let pciid = pci_read(0x00, 0x1f, 0x6, 0x00).unwrap();
-                    if pciid == 0x1a1e8086 || pciid == 0x1a1f8086 {
-                        "system76/gaze17-3060-b".to_string()
-                    } else {
-                        "system76/gaze17-3060".to_string()
-                    }
-                }
-                "NPxxRNx" => "system76/gaze18".to_string(),
-                "NPxxSNx" => "system76/addw3".to_string(),
-                "V3x0SNx" => "system76/addw4".to_string(),
-                "NS50MU" => "system76/darp7".to_string(),
-                "NS50_70PU" => "system76/darp8".to_string(),
-                "NS50_70AU" => "system76/darp9".to_string(),
-                "V5x0TU" => {
-                    // Check SPI device at 1f.5 for Arrow Lake or Meteor Lake
-                    match pci_read(0x00, 0x1f, 0x5, 0x00).unwrap() {
-                        // 0x7723 is Arrow Lake (darp11)
-                        0x77238086 => {
-                            // If GPP_E2 is high, this is the 16 inch variant
-                            unsafe {
-                                let sideband = Sideband::new(0xE000_0000);
-                                if sideband.gpio(0xD2, 0x36) & 2 == 2 {
-                                    "system76/darp11-b".to_string()
-                                } else {
-                                    "system76/darp11".to_string()
-                                }
-                            }
-                        },
-                        // 0x7e23 is Meteor Lake (darp10)
-                        0x7e238086 => {
-                            // If GPP_E2 is high, this is the 16 inch variant
-                            unsafe {
-                                let sideband = Sideband::new(0xE000_0000);
-                                if sideband.gpio(0xD2, 0x36) & 2 == 2 {
-                                    "system76/darp10-b".to_string()
-                                } else {
-                                    "system76/darp10".to_string()
-                                }
-                            }
-                        },
-                        _ => model.to_string(),
-                    }
-                }
-                "NV40Mx" | "NV40Mx-DV" | "NV40MJ" => "system76/galp5".to_string(),
-                "NV4xPZ" => "system76/galp6".to_string(),
-                "NV40RZ" => "system76/galp7".to_string(),
-                "PB50Ex" => "system76/addw1".to_string(),
-                "PBx0Dx2" => "system76/addw2".to_string(),
-                "P950Ex" => "system76/oryp5".to_string(),
-                "PCx0Dx2" => "system76/oryp6".to_string(),
-                "PCx0Dx" => "system76/oryp7".to_string(),
-                "PCxxHX" => "system76/oryp8".to_string(),
-                "PDxxPNx" => {
-                    // If the unit uses DDR5, it is oryp10
-                    let mem = memory_kind().unwrap_or(0x02);
-                    match mem {
-                        0x1A => "system76/oryp9".to_string(),
-                        0x22 => "system76/oryp10".to_string(),
-                        _ => model.to_string(),
-                    }
-                }
-                "PE6xRNx" => "system76/oryp11".to_string(),
-                "PE60SNx" => "system76/oryp12".to_string(),
-                "PDxxSNx" => "system76/serw13".to_string(),
-                "V2xxRNP" => "system76/gaze20".to_string(),
-                "X170SM-G" => "system76/bonw14".to_string(),
-                "X370SNx" => "system76/bonw15".to_string(),
-                "X370SNx1" => "system76/bonw15-b".to_string(),
-                "X58xWNx" => "system76/bonw16".to_string(),
-                _ => model.to_string(),
+        // Map ODM board names to System76 models
+        let table = match alias::load_table() {
+            Ok(table) => table,
+            Err(err) => {
+                println!("{}: failed to load EC models: {:?}", self.name(), err);
+                return false;
             }
         };
+        let hardware = HostHardware {
+            ec_version: &self.version,
+        };
+        let normalize_model = |model: &str| table.normalize(model, &hardware);
         let firmware_model = self.ec.firmware_model(data);
         !self.model.is_empty()
             && !self.version.is_empty()
@@ -349,6 +334,33 @@ impl EcComponent {
     }
 }
 
+/// Hardware of this system, checked by EC model aliases
+struct HostHardware<'a> {
+    ec_version: &'a str,
+}
+
+impl Hardware for HostHardware<'_> {
+    fn pci_id(&self, bus: u8, dev: u8, func: u8) -> Option<u32> {
+        pci_read(bus, dev, func, 0x00).ok()
+    }
+
+    fn gpio_high(&self, port: u8, pad: u8) -> bool {
+        // Bit 1 of the pad configuration is the RX state
+        unsafe {
+            let sideband = Sideband::new(0xE000_0000);
+            sideband.gpio(port, pad) & 2 == 2
+        }
+    }
+
+    fn memory_kind(&self) -> Option<u8> {
+        memory_kind().ok()
+    }
+
+    fn ec_version(&self) -> &str {
+        self.ec_version
+    }
+}
+
 struct SpiLegacy<T: Timeout> {
     pmc: ectool::Pmc<UefiTimeout>,
     timeout: T,
@@ -452,17 +464,16 @@ impl<T: Timeout> SpiLegacy<T> {
     }
 }
 
-unsafe fn flash_legacy(firmware_data: &[u8]) -> core::result::Result<(), ectool::Error> {
+unsafe fn flash_legacy(
+    firmware_data: &[u8],
+    flash_size: Option<usize>,
+) -> core::result::Result<(), ectool::Error> {
     let mut spi = unsafe { SpiLegacy::new(UefiTimeout::new(1_000_000)) };
 
     let new_rom = firmware_data.to_vec();
 
-    // XXX: Get flash size programatically?
     let rom_size = new_rom.len();
-    if rom_size % 1024 != 0 {
-        println!("ROM size of {} is not valid", rom_size);
-        return Err(ectool::Error::Verify);
-    }
+    check_rom_size(rom_size, flash_size, true)?;
 
     println!("Entering scratch ROM");
     let _ = unsafe { spi.scratch()? };
@@ -495,10 +506,10 @@ unsafe fn flash_legacy(firmware_data: &[u8]) -> core::result::Result<(), ectool:
     let mut written = vec![0; rom_size];
     unsafe { spi.read(&mut written)? };
     for (addr, byte) in written.iter().enumerate() {
-        if *byte != written[addr] {
+        if *byte != new_rom[addr] {
             println!(
                 "Failed to write ROM: {:04X} is {:02X} not {:02X}",
-                addr, byte, written[addr],
+                addr, byte, new_rom[addr],
             );
             return Err(ectool::Error::Verify);
         }
@@ -536,6 +547,112 @@ pub unsafe fn security_unlock() -> core::result::Result<(), ectool::Error> {
     }
 }
 
+unsafe fn read_jedec_id<S: Spi>(spi: &mut S) -> core::result::Result<[u8; 3], ectool::Error> {
+    let mut id = [0; 3];
+    unsafe {
+        spi.reset()?;
+        spi.write(&[SPI_READ_JEDEC_ID])?;
+        spi.read(&mut id)?;
+        spi.reset()?;
+    }
+    Ok(id)
+}
+
+/// Read the JEDEC ID of the flash behind an EC SPI bus, returning its capacity
+unsafe fn chip_size<S: Spi>(spi: &mut S) -> Option<usize> {
+    let id = match unsafe { read_jedec_id(spi) } {
+        Ok(id) => id,
+        Err(err) => {
+            println!("EC flash: failed to read JEDEC ID: {:X?}", err);
+            return None;
+        }
+    };
+
+    match ec_flash::jedec_size(id) {
+        Some(size) => {
+            println!(
+                "EC flash: {} {:02X}{:02X}{:02X}, {} KB",
+                ec_flash::jedec_manufacturer(id[0]),
+                id[0],
+                id[1],
+                id[2],
+                size / 1024
+            );
+            Some(size)
+        }
+        None => {
+            println!(
+                "EC flash: unknown JEDEC ID {:02X}{:02X}{:02X}",
+                id[0], id[1], id[2]
+            );
+            None
+        }
+    }
+}
+
+/// Refuse images that do not fit the flash, or do not fill it if `fill` is set
+fn check_rom_size(
+    rom_size: usize,
+    flash_size: Option<usize>,
+    fill: bool,
+) -> core::result::Result<(), ectool::Error> {
+    if let Err(err) = ec_flash::check_image_size(rom_size, flash_size, fill) {
+        println!("{}", err);
+        return Err(ectool::Error::Verify);
+    }
+    if flash_size.is_none() {
+        println!("Flash size unknown, using ROM size of {}", rom_size);
+    }
+    Ok(())
+}
+
+/// Signature checked by the ITE boot ROM before it runs the firmware
+const ITE_SIGNATURE: [u8; 8] = [0xA5, 0xA5, 0xA5, 0xA5, 0xA5, 0xA5, 0x85, 0x12];
+
+/// Address of the firmware signature, which is in the first sector if it is not found
+fn signature_address(data: &[u8]) -> usize {
+    data.windows(ITE_SIGNATURE.len())
+        .step_by(16)
+        .position(|window| window == ITE_SIGNATURE)
+        .map_or(0, |i| i * 16)
+}
+
+impl<S: Spi> RomReader for SpiRom<'_, S, UefiTimeout> {
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> core::result::Result<(), String> {
+        let count = unsafe { SpiRom::read_at(self, offset as u32, buf) }
+            .map_err(|err| format!("read at {:X}: {:X?}", offset, err))?;
+        if count != buf.len() {
+            return Err(format!(
+                "read count {} did not match size {}",
+                count,
+                buf.len()
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<S: Spi> RomWriter for SpiRom<'_, S, UefiTimeout> {
+    fn erase_sector(&mut self, address: usize) -> core::result::Result<(), String> {
+        unsafe { SpiRom::erase_sector(self, address as u32) }
+            .map_err(|err| format!("erase at {:X}: {:X?}", address, err))?;
+        Ok(())
+    }
+
+    fn write_sector(&mut self, address: usize, data: &[u8]) -> core::result::Result<(), String> {
+        let count = unsafe { SpiRom::write_at(self, address as u32, data) }
+            .map_err(|err| format!("write at {:X}: {:X?}", address, err))?;
+        if count != data.len() {
+            return Err(format!(
+                "write count {} did not match size {}",
+                count,
+                data.len()
+            ));
+        }
+        Ok(())
+    }
+}
+
 unsafe fn flash_read<S: Spi>(
     spi: &mut SpiRom<S, UefiTimeout>,
     rom: &mut [u8],
@@ -559,22 +676,14 @@ unsafe fn flash_read<S: Spi>(
     Ok(())
 }
 
-unsafe fn flash(
-    firmware_data: &[u8],
-    target: SpiTarget,
-) -> core::result::Result<(), ectool::Error> {
+/// Open a session with a System76 EC, printing its board and version, which it can only report
+/// before entering the scratch ROM
+unsafe fn open_ec() -> core::result::Result<ectool::Ec<AccessLpcDirect<UefiTimeout>>, ectool::Error>
+{
     let access = unsafe { AccessLpcDirect::new(UefiTimeout::new(100_000))? };
     let mut ec = unsafe { ectool::Ec::new(access)? };
     let data_size = unsafe { ec.access().data_size() };
 
-    println!(
-        "Programming EC {} ROM",
-        match target {
-            SpiTarget::Main => "Main",
-            SpiTarget::Backup => "Backup",
-        }
-    );
-
     {
         let mut data = vec![0; data_size];
         let size = unsafe { ec.board(&mut data)? };
@@ -591,66 +700,113 @@ unsafe fn flash(
         println!("ec version: {:?}", str::from_utf8(ec_version));
     }
 
-    let new_rom = firmware_data.to_vec();
+    Ok(ec)
+}
 
-    // XXX: Get flash size programatically?
-    let rom_size = new_rom.len();
-    if rom_size % 1024 != 0 {
-        println!("ROM size of {} is not valid", rom_size);
-        return Err(ectool::Error::Verify);
+fn target_name(target: SpiTarget) -> &'static str {
+    match target {
+        SpiTarget::Main => "Main",
+        SpiTarget::Backup => "Backup",
     }
+}
 
-    let mut spi_bus = unsafe { ec.spi(SpiTarget::Main, true)? };
+/// Read the whole main or backup ROM of a System76 EC, checking the size against that chip
+unsafe fn read_target<A: Access>(
+    ec: &mut ectool::Ec<A>,
+    target: SpiTarget,
+    rom_size: usize,
+) -> core::result::Result<Vec<u8>, ectool::Error> {
+    // Reading does not require the scratch ROM, which would need an EC reset to leave
+    let mut spi_bus = unsafe { ec.spi(target, false)? };
+    check_rom_size(rom_size, unsafe { chip_size(&mut spi_bus) }, false)?;
     let mut spi = SpiRom::new(&mut spi_bus, UefiTimeout::new(1_000_000));
     let sector_size = spi.sector_size();
 
     let mut rom = vec![0xFF; rom_size];
     unsafe { flash_read(&mut spi, &mut rom, sector_size)? };
+    Ok(rom)
+}
+
+/// Read the whole main or backup ROM of a System76 EC in a new session
+unsafe fn read_rom(
+    target: SpiTarget,
+    rom_size: usize,
+) -> core::result::Result<Vec<u8>, ectool::Error> {
+    let access = unsafe { AccessLpcDirect::new(UefiTimeout::new(100_000))? };
+    let mut ec = unsafe { ectool::Ec::new(access)? };
+    unsafe { read_target(&mut ec, target, rom_size) }
+}
+
+/// Read the whole ROM of a legacy EC, which is left running from the scratch ROM
+unsafe fn dump_legacy(rom_size: usize) -> core::result::Result<Vec<u8>, ectool::Error> {
+    let mut spi = unsafe { SpiLegacy::new(UefiTimeout::new(1_000_000)) };
 
-    // Program chip, sector by sector
-    //TODO: write signature last
+    println!("Entering scratch ROM");
+    let _ = unsafe { spi.scratch()? };
+
+    println!("Reading ROM");
+    let mut rom = vec![0xFF; rom_size];
+    unsafe { spi.read(&mut rom)? };
+    Ok(rom)
+}
+
+/// Print the plan for flashing the main ROM of a System76 EC, without entering the scratch ROM
+unsafe fn plan(firmware_data: &[u8]) -> core::result::Result<(), ectool::Error> {
+    let mut ec = unsafe { open_ec()? };
     {
-        let mut address = 0;
-        while address < rom_size {
-            print!("\rSPI Write {}K", address / 1024);
+        let mut spi_bus = unsafe { ec.spi(SpiTarget::Main, false)? };
+        check_rom_size(
+            firmware_data.len(),
+            unsafe { chip_size(&mut spi_bus) },
+            false,
+        )?;
+    }
+    unsafe { program(&mut ec, SpiTarget::Main, firmware_data, true) }
+}
 
-            let next_address = address + sector_size;
+/// Program a ROM of a System76 EC whose size has been checked. Once the scratch ROM is entered,
+/// the EC only answers SPI commands until it is reset, so every ROM is programmed with the
+/// session that entered it.
+unsafe fn program<A: Access>(
+    ec: &mut ectool::Ec<A>,
+    target: SpiTarget,
+    firmware_data: &[u8],
+    dry_run: bool,
+) -> core::result::Result<(), ectool::Error> {
+    println!(
+        "{} EC {} ROM",
+        if dry_run { "Planning" } else { "Programming" },
+        target_name(target)
+    );
 
-            let mut matches = true;
-            let mut erased = true;
-            let mut new_erased = true;
-            for i in address..next_address {
-                if rom[i] != new_rom[i] {
-                    matches = false;
-                }
-                if rom[i] != 0xFF {
-                    erased = false;
-                }
-                if new_rom[i] != 0xFF {
-                    new_erased = false;
-                }
-            }
+    let new_rom = firmware_data.to_vec();
+    let rom_size = new_rom.len();
 
-            if !matches {
-                if !erased {
-                    unsafe { spi.erase_sector(address as u32)? };
-                }
-                if !new_erased {
-                    let count =
-                        unsafe { spi.write_at(address as u32, &new_rom[address..next_address])? };
-                    if count != sector_size {
-                        println!(
-                            "\nWrite count {} did not match sector size {}",
-                            count, sector_size
-                        );
-                        return Err(ectool::Error::Verify);
-                    }
-                }
-            }
+    // Reading does not require the scratch ROM, which would need an EC reset to leave
+    let mut spi_bus = unsafe { ec.spi(target, !dry_run)? };
+    let mut spi = SpiRom::new(&mut spi_bus, UefiTimeout::new(1_000_000));
+    let sector_size = spi.sector_size();
+
+    let mut rom = vec![0xFF; rom_size];
+    unsafe { flash_read(&mut spi, &mut rom, sector_size)? };
+
+    let mut plan = Plan::new(&rom, &new_rom, sector_size);
+    plan.signature_last(signature_address(&new_rom), &rom, &new_rom);
+    if dry_run {
+        print!("{}", plan.report("EC", &EC_TIMING));
+        return Ok(());
+    }
 
-            address = next_address;
+    // Program chip, sector by sector, with the signature sector erased first and written last
+    {
+        let result = plan.program_signature_last(&mut spi, &new_rom, &mut |address| {
+            print!("\rSPI Write {}K", address / 1024);
+        });
+        println!();
+        if let Err(err) = result {
+            println!("Failed to program: {}", err);
+            return Err(ectool::Error::Verify);
         }
-        println!("\rSPI Write {}K", address / 1024);
 
         // Verify chip write
         unsafe { flash_read(&mut spi, &mut rom, sector_size)? };
@@ -665,13 +821,7 @@ unsafe fn flash(
         }
     }
 
-    println!(
-        "Successfully programmed EC {} ROM",
-        match target {
-            SpiTarget::Main => "Main",
-            SpiTarget::Backup => "Backup",
-        }
-    );
+    println!("Successfully programmed EC {} ROM", target_name(target));
 
     Ok(())
 }
@@ -718,6 +868,17 @@ impl I2EC {
     }
 }
 
+/// Reset a legacy EC left in the scratch ROM by dump, once every dump has been written
+pub fn dump_reset() {
+    if SCRATCH_RESET.load(Ordering::SeqCst) {
+        println!("System will shut off in 5 seconds");
+        let _ = (std::system_table().BootServices.Stall)(5_000_000);
+        unsafe {
+            watchdog_reset(true);
+        }
+    }
+}
+
 unsafe fn watchdog_reset(global: bool) {
     unsafe {
         let mut i2ec = I2EC::new();
@@ -736,6 +897,114 @@ unsafe fn watchdog_reset(global: bool) {
     }
 }
 
+impl EcComponent {
+    /// Write a ROM read by dump to the dumps directory
+    fn save_dump(&self, kind: &str, rom: &[u8]) -> Result<()> {
+        let path = format!(
+            "{}\\{}-{}-{}.rom",
+            DUMPDIR,
+            file::sanitize(&self.model),
+            kind,
+            file::timestamp()
+        );
+        println!("{} DUMP: {}", self.name(), path);
+        file::create_dir(DUMPDIR)?;
+        let mut writer = file::VerifiedWriter::create(&path)?;
+        writer.write(rom)?;
+        let hash = writer.finish()?;
+        println!("{} DUMP: SHA-256 {}", self.name(), hash);
+        Ok(())
+    }
+
+    /// Flash the main ROM, then keep the backup ROM in sync or one version behind on boards that
+    /// have one, all in one EC session. If flashing the main ROM fails, it is recovered from the
+    /// old firmware, or from the backup ROM if the old firmware is not valid.
+    unsafe fn flash_system76(&self, firmware_data: &[u8]) -> Result<()> {
+        let keep_previous = find(ECBACKUPPREVIOUS).is_ok();
+        let rom_size = firmware_data.len();
+
+        let mut ec = match unsafe { open_ec() } {
+            Ok(ec) => ec,
+            Err(err) => {
+                println!("{} Flash Error: {:X?}", self.name(), err);
+                return Err(Status::DEVICE_ERROR);
+            }
+        };
+
+        // Both ROMs are read before the scratch ROM is entered
+        println!("Reading EC Main ROM");
+        let main = match unsafe { read_target(&mut ec, SpiTarget::Main, rom_size) } {
+            Ok(main) => main,
+            Err(err) => {
+                println!("{} Flash Error: {:X?}", self.name(), err);
+                return Err(Status::DEVICE_ERROR);
+            }
+        };
+        let main_valid = Firmware::new(&main).is_some();
+
+        // A backup chip is detected from its JEDEC ID, as the backup ROM may be blank or corrupt
+        let backup_chip = match unsafe { ec.spi(SpiTarget::Backup, false) } {
+            Ok(mut spi_bus) => unsafe { chip_size(&mut spi_bus) }.is_some(),
+            Err(_) => false,
+        };
+        let backup = if backup_chip {
+            println!("Reading EC Backup ROM");
+            match unsafe { read_target(&mut ec, SpiTarget::Backup, rom_size) } {
+                Ok(backup) => Some(backup),
+                Err(err) => {
+                    println!("{}: backup ROM not usable: {:X?}", self.name(), err);
+                    None
+                }
+            }
+        } else {
+            println!("{}: no backup ROM", self.name());
+            None
+        };
+
+        let recovery = if main_valid {
+            Some(("old firmware", main.as_slice()))
+        } else {
+            backup
+                .as_deref()
+                .filter(|backup| Firmware::new(backup).is_some())
+                .map(|backup| ("backup ROM", backup))
+        };
+
+        if let Err(err) = unsafe { program(&mut ec, SpiTarget::Main, firmware_data, false) } {
+            println!("{} Flash Error: {:X?}", self.name(), err);
+
+            match recovery {
+                Some((source, recovery)) => {
+                    println!("{}: recovering main ROM from {}", self.name(), source);
+                    if let Err(err) = unsafe { program(&mut ec, SpiTarget::Main, recovery, false) }
+                    {
+                        println!("{} Recovery Error: {:X?}", self.name(), err);
+                    }
+                }
+                None => println!("{}: no valid firmware to recover from", self.name()),
+            }
+            return Err(Status::DEVICE_ERROR);
+        }
+
+        // The backup ROM is only changed once the new main ROM is verified
+        let backup_data = match backup {
+            // Keep the backup one version behind by copying the old main firmware
+            Some(_) if keep_previous && main_valid => Some(main.as_slice()),
+            // Otherwise, including when the backup ROM is blank or corrupt, it gets the new one
+            Some(_) => Some(firmware_data),
+            None => None,
+        };
+        if let Some(backup_data) = backup_data {
+            // Main ROM is already updated, so a backup failure does not fail the update
+            if let Err(err) = unsafe { program(&mut ec, SpiTarget::Backup, backup_data, false) } {
+                println!("{} Backup Flash Error: {:X?}", self.name(), err);
+            }
+        }
+
+        Ok(())
+    }
+}
+
 impl Component for EcComponent {
     fn name(&self) -> &str {
         if self.master { "EC" } else { "EC2" }
@@ -753,11 +1022,103 @@ impl Component for EcComponent {
         &self.version
     }
 
+    fn new_version(&self) -> String {
+        self.new_version.borrow().clone()
+    }
+
+    fn restore(&self) -> bool {
+        false
+    }
+
     fn validate(&self) -> Result<bool> {
         let data = load(self.path())?;
+        signature::verify(self.path(), &data)?;
+        *self.new_version.borrow_mut() = self.ec.firmware_version(&data);
         Ok(self.validate_data(data))
     }
 
+    fn dry_run(&self) -> Result<()> {
+        let firmware_data = load(self.path())?;
+        match &self.ec {
+            EcKind::System76(_ec, _pmc) => match unsafe { plan(&firmware_data) } {
+                Ok(()) => Ok(()),
+                Err(err) => {
+                    println!("{} Plan Error: {:X?}", self.name(), err);
+                    Err(Status::DEVICE_ERROR)
+                }
+            },
+            EcKind::Legacy(_ec) => {
+                println!(
+                    "{}: whole ROM of {} KB would be erased and written",
+                    self.name(),
+                    firmware_data.len() / 1024
+                );
+                Ok(())
+            }
+            EcKind::Pang(_pmc, _system_version) => {
+                println!(
+                    "{}: flashed by vendor tools, no plan available",
+                    self.name()
+                );
+                Ok(())
+            }
+            EcKind::Unknown => {
+                println!("{} Failed to plan EcKind::Unknown", self.name());
+                Err(Status::DEVICE_ERROR)
+            }
+        }
+    }
+
+    fn dump(&self) -> Result<()> {
+        match &self.ec {
+            // EC2 finds the same System76 EC, and legacy ROMs are read through the main EC
+            EcKind::System76(_ec, _pmc) | EcKind::Legacy(_ec) if !self.master => {
+                println!("{}: same EC as EC, nothing dumped", self.name());
+                Ok(())
+            }
+            EcKind::System76(_ec, _pmc) => {
+                let rom_size = self.flash_size().unwrap_or(EC_DUMP_SIZE);
+                match unsafe { read_rom(SpiTarget::Main, rom_size) } {
+                    Ok(rom) => self.save_dump("ec", &rom)?,
+                    Err(err) => {
+                        println!("{} Dump Error: {:X?}", self.name(), err);
+                        return Err(Status::DEVICE_ERROR);
+                    }
+                }
+
+                // Boards without a backup ROM have no chip to detect, or fail to read it
+                let backup_size = unsafe { self.ec.flash_size(self.master, SpiTarget::Backup) };
+                match backup_size.map(|size| unsafe { read_rom(SpiTarget::Backup, size) }) {
+                    Some(Ok(rom)) => self.save_dump("ec-backup", &rom)?,
+                    Some(Err(err)) => println!("{}: no backup ROM: {:X?}", self.name(), err),
+                    None => println!("{}: no backup ROM", self.name()),
+                }
+                Ok(())
+            }
+            EcKind::Legacy(_ec) => {
+                let rom_size = self.flash_size().unwrap_or(EC_DUMP_SIZE);
+                let result = unsafe { dump_legacy(rom_size) };
+                // The scratch ROM can only be left by resetting the EC, even if reading failed
+                SCRATCH_RESET.store(true, Ordering::SeqCst);
+                match result {
+                    Ok(rom) => self.save_dump("ec", &rom),
+                    Err(err) => {
+                        println!("{} Dump Error: {:X?}", self.name(), err);
+                        Err(Status::DEVICE_ERROR)
+                    }
+                }
+            }
+            EcKind::Pang(_pmc, _system_version) => {
+                println!("{}: read by vendor tools, nothing dumped", self.name());
+                Ok(())
+            }
+            EcKind::Unknown => {
+                println!("{}: not found, nothing dumped", self.name());
+                Ok(())
+            }
+        }
+    }
+
     fn flash(&self) -> Result<()> {
         let mut requires_reset = false;
 
@@ -788,20 +1149,13 @@ impl Component for EcComponent {
                 // System76 EC requires reset to load new firmware
                 requires_reset = true;
 
-                // Flash main ROM
-                match unsafe { flash(&firmware_data, SpiTarget::Main) } {
-                    Ok(()) => Ok(()),
-                    Err(err) => {
-                        println!("{} Flash Error: {:X?}", self.name(), err);
-                        Err(Status::DEVICE_ERROR)
-                    }
-                }
+                unsafe { self.flash_system76(&firmware_data) }
             }
             EcKind::Legacy(_ec) => {
                 requires_reset = true;
 
                 // Use open source flashing code
-                match unsafe { flash_legacy(&firmware_data) } {
+                match unsafe { flash_legacy(&firmware_data, self.flash_size()) } {
                     Ok(()) => Ok(()),
                     Err(err) => {
                         println!("{} Flash Error: {:X?}", self.name(), err);
@@ -0,0 +1,160 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use alloc::string::String;
+use core::ptr;
+use std::prelude::*;
+
+use super::protection::spi_bar;
+
+// Hardware sequencing registers, for cycles intel-spi does not issue
+const SPIBAR_HSFSTS_CTL: usize = 0x04;
+const SPIBAR_FADDR: usize = 0x08;
+const SPIBAR_FDATA0: usize = 0x10;
+
+const HSFSTS_FDONE: u32 = 1 << 0;
+const HSFSTS_FCERR: u32 = 1 << 1;
+const HSFSTS_H_AEL: u32 = 1 << 2;
+const HSFSTS_H_SCIP: u32 = 1 << 5;
+const HSFCTL_FGO: u32 = 1 << 16;
+const HSFCTL_FCYCLE_SHIFT: u32 = 17;
+const HSFCTL_FDBC_SHIFT: u32 = 24;
+
+// Cycle types of Skylake and later controllers
+const FCYCLE_ERASE_64K: u32 = 4;
+const FCYCLE_READ_SFDP: u32 = 5;
+
+const SFDP_SIGNATURE: u32 = 0x5044_4653;
+const SFDP_BFPT_ID: u8 = 0x00;
+
+pub const BLOCK_SIZE: usize = 64 * 1024;
+
+/// Polling interval and limit for a cycle, long enough for the slowest 64 KiB erase
+const POLL_US: usize = 10;
+const POLL_LIMIT: usize = 500_000;
+
+fn stall(us: usize) {
+    let _ = (std::system_table().BootServices.Stall)(us);
+}
+
+/// SPI cycles issued directly through the controller registers
+pub struct SpiCycles {
+    bar: usize,
+}
+
+impl SpiCycles {
+    /// Only Skylake and later controllers, which use a version 2 descriptor, have 64 KiB erase
+    /// and SFDP read cycles
+    pub fn new(descriptor_v2: bool) -> Option<Self> {
+        if !descriptor_v2 {
+            return None;
+        }
+        Some(Self { bar: spi_bar()? })
+    }
+
+    unsafe fn read(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.bar + offset) as *const u32) }
+    }
+
+    unsafe fn write(&self, offset: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.bar + offset) as *mut u32, value) }
+    }
+
+    unsafe fn wait(&self, mask: u32) -> core::result::Result<u32, String> {
+        for _ in 0..POLL_LIMIT {
+            let hsfsts_ctl = unsafe { self.read(SPIBAR_HSFSTS_CTL) };
+            if hsfsts_ctl & mask != 0 {
+                return Ok(hsfsts_ctl);
+            }
+            stall(POLL_US);
+        }
+        Err("timed out".to_string())
+    }
+
+    unsafe fn cycle(
+        &self,
+        fcycle: u32,
+        address: usize,
+        len: usize,
+    ) -> core::result::Result<(), String> {
+        unsafe {
+            // Wait for any cycle in progress
+            for _ in 0..POLL_LIMIT {
+                if self.read(SPIBAR_HSFSTS_CTL) & HSFSTS_H_SCIP == 0 {
+                    break;
+                }
+                stall(POLL_US);
+            }
+
+            self.write(SPIBAR_FADDR, address as u32);
+            // Status bits are cleared by writing one
+            self.write(
+                SPIBAR_HSFSTS_CTL,
+                HSFSTS_FDONE
+                    | HSFSTS_FCERR
+                    | HSFSTS_H_AEL
+                    | (fcycle << HSFCTL_FCYCLE_SHIFT)
+                    | ((len.max(1) as u32 - 1) << HSFCTL_FDBC_SHIFT)
+                    | HSFCTL_FGO,
+            );
+
+            let hsfsts_ctl = self.wait(HSFSTS_FDONE | HSFSTS_FCERR | HSFSTS_H_AEL)?;
+            if hsfsts_ctl & (HSFSTS_FCERR | HSFSTS_H_AEL) != 0 {
+                return Err(format!("cycle error {:#X}", hsfsts_ctl & 0xFFFF));
+            }
+        }
+        Ok(())
+    }
+
+    /// Read from the Serial Flash Discoverable Parameters of the chip
+    pub fn read_sfdp(&self, address: usize, buf: &mut [u8]) -> core::result::Result<(), String> {
+        for (i, chunk) in buf.chunks_mut(64).enumerate() {
+            unsafe {
+                self.cycle(FCYCLE_READ_SFDP, address + i * 64, chunk.len())?;
+                for (j, bytes) in chunk.chunks_mut(4).enumerate() {
+                    let word = self.read(SPIBAR_FDATA0 + j * 4).to_le_bytes();
+                    bytes.copy_from_slice(&word[..bytes.len()]);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Erase an aligned 64 KiB block
+    pub fn erase_block(&self, address: usize) -> core::result::Result<(), String> {
+        if address % BLOCK_SIZE != 0 {
+            return Err(format!("block {:#08X} is not aligned", address));
+        }
+        unsafe { self.cycle(FCYCLE_ERASE_64K, address, 0) }
+    }
+
+    /// Erase sizes supported by the chip, from the SFDP basic flash parameter table
+    pub fn erase_sizes(&self) -> core::result::Result<Vec<usize>, String> {
+        let mut header = [0; 16];
+        self.read_sfdp(0, &mut header)?;
+        let signature = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        if signature != SFDP_SIGNATURE {
+            return Err(format!("invalid SFDP signature {:#X}", signature));
+        }
+
+        // First parameter header must be the basic flash parameter table
+        if header[8] != SFDP_BFPT_ID || header[11] < 9 {
+            return Err("missing SFDP basic flash parameter table".to_string());
+        }
+        let pointer = u32::from_le_bytes([header[12], header[13], header[14], 0]) as usize;
+
+        let mut bfpt = [0; 36];
+        self.read_sfdp(pointer, &mut bfpt)?;
+
+        // DWORDs 8 and 9 have four erase types, each a size exponent and opcode
+        let mut sizes = Vec::new();
+        for i in 0..4 {
+            let exponent = bfpt[28 + i * 2];
+            if exponent != 0 && exponent < 32 {
+                sizes.push(1 << exponent);
+            }
+        }
+        sizes.sort_unstable();
+        sizes.dedup();
+        Ok(sizes)
+    }
+}
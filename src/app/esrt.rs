@@ -0,0 +1,267 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use alloc::string::String;
+use core::fmt::Write;
+use core::{mem, slice, str};
+use std::ffi::wstr;
+use std::prelude::*;
+
+const ESRT_TABLE_GUID: Guid = Guid(
+    0xb122_a263,
+    0x3661,
+    0x4a68,
+    [0x99, 0x29, 0x78, 0xf8, 0xb0, 0xd6, 0x21, 0x80],
+);
+
+// Resources updated by a submitted capsule are kept in a non-volatile UEFI variable, so the
+// result can be reported after the reset that processes the capsule
+static PENDING_NAME: &str = "FirmwareUpdateCapsule";
+static PENDING_GUID: Guid = Guid(
+    0xda20_223a,
+    0xa45d,
+    0x4a24,
+    [0xbd, 0xa8, 0xc5, 0x74, 0x3a, 0x62, 0xbd, 0x35],
+);
+
+const VARIABLE_NON_VOLATILE: u32 = 1 << 0;
+const VARIABLE_BOOTSERVICE_ACCESS: u32 = 1 << 1;
+const VARIABLE_RUNTIME_ACCESS: u32 = 1 << 2;
+
+const LAST_ATTEMPT_STATUS_SUCCESS: u32 = 0;
+
+#[allow(dead_code)]
+#[repr(C, packed)]
+struct EsrtHeader {
+    count: u32,
+    count_max: u32,
+    version: u64,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct EsrtEntry {
+    pub fw_class: Guid,
+    pub fw_type: u32,
+    pub fw_version: u32,
+    pub lowest_supported_fw_version: u32,
+    pub capsule_flags: u32,
+    pub last_attempt_version: u32,
+    pub last_attempt_status: u32,
+}
+
+impl EsrtEntry {
+    fn type_name(&self) -> &'static str {
+        match self.fw_type {
+            0 => "unknown",
+            1 => "system firmware",
+            2 => "device firmware",
+            3 => "UEFI driver",
+            _ => "invalid",
+        }
+    }
+
+    fn status_name(&self) -> &'static str {
+        match self.last_attempt_status {
+            LAST_ATTEMPT_STATUS_SUCCESS => "success",
+            1 => "unsuccessful",
+            2 => "insufficient resources",
+            3 => "incorrect version",
+            4 => "invalid format",
+            5 => "authentication error",
+            6 => "AC power too low",
+            7 => "battery too low",
+            8 => "unsatisfied dependencies",
+            0x1000..=0x4000 => "vendor error",
+            _ => "invalid",
+        }
+    }
+
+    /// Last capsule for this resource was processed, and the resource is now at its version
+    pub fn last_attempt_succeeded(&self) -> bool {
+        let (status, version, last_version) = (
+            self.last_attempt_status,
+            self.fw_version,
+            self.last_attempt_version,
+        );
+        status == LAST_ATTEMPT_STATUS_SUCCESS && version == last_version
+    }
+
+    fn line(&self) -> String {
+        let (fw_class, fw_version, lowest, last_version, last_status) = (
+            self.fw_class,
+            self.fw_version,
+            self.lowest_supported_fw_version,
+            self.last_attempt_version,
+            self.last_attempt_status,
+        );
+        format!(
+            "{} {}, version {:#X}, lowest supported {:#X}, last attempt {:#X} {} ({:#X})",
+            guid_string(&fw_class),
+            self.type_name(),
+            fw_version,
+            lowest,
+            last_version,
+            self.status_name(),
+            last_status
+        )
+    }
+}
+
+/// GUID in registry format, as used by OS tools
+pub fn guid_string(guid: &Guid) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+        guid.0,
+        guid.1,
+        guid.2,
+        guid.3[0],
+        guid.3[1],
+        guid.3[2],
+        guid.3[3],
+        guid.3[4],
+        guid.3[5],
+        guid.3[6],
+        guid.3[7]
+    )
+}
+
+fn parse_guid(s: &str) -> Option<Guid> {
+    let parts: Vec<&str> = s.split('-').collect();
+    if parts.len() != 5 || parts[3].len() != 4 || parts[4].len() != 12 {
+        return None;
+    }
+    let mut bytes = [0; 8];
+    let tail = format!("{}{}", parts[3], parts[4]);
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(tail.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(Guid(
+        u32::from_str_radix(parts[0], 16).ok()?,
+        u16::from_str_radix(parts[1], 16).ok()?,
+        u16::from_str_radix(parts[2], 16).ok()?,
+        bytes,
+    ))
+}
+
+/// Firmware resources from the EFI System Resource Table, if the firmware provides one
+pub fn entries() -> Vec<EsrtEntry> {
+    for table in std::system_table().config_tables() {
+        if table.VendorGuid != ESRT_TABLE_GUID {
+            continue;
+        }
+
+        let entries = unsafe {
+            let header = &*(table.VendorTable as *const EsrtHeader);
+            slice::from_raw_parts(
+                (table.VendorTable as *const u8).add(mem::size_of::<EsrtHeader>())
+                    as *const EsrtEntry,
+                header.count as usize,
+            )
+        };
+        return entries.to_vec();
+    }
+
+    vec![]
+}
+
+/// Find the resource a capsule with this GUID updates
+pub fn find(fw_class: &Guid) -> Option<EsrtEntry> {
+    entries().into_iter().find(|entry| {
+        let entry_class = entry.fw_class;
+        entry_class == *fw_class
+    })
+}
+
+/// Print the firmware resources, returning the same text for the log
+pub fn report() -> String {
+    let mut log = String::new();
+    let entries = entries();
+    if entries.is_empty() {
+        println!("ESRT: not provided by firmware");
+        let _ = writeln!(log, "ESRT: not provided by firmware");
+    }
+    for entry in entries.iter() {
+        let line = entry.line();
+        println!("ESRT: {}", line);
+        let _ = writeln!(log, "ESRT: {}", line);
+    }
+
+    if let Some(classes) = load_pending() {
+        for class in classes {
+            let result = match entries.iter().find(|entry| {
+                let entry_class = entry.fw_class;
+                entry_class == class
+            }) {
+                Some(entry) if entry.last_attempt_succeeded() => "succeeded",
+                Some(_) => "failed",
+                None => "failed, resource not found",
+            };
+            let line = format!("last capsule for {} {}", guid_string(&class), result);
+            println!("ESRT: {}", line);
+            let _ = writeln!(log, "ESRT: {}", line);
+        }
+        if let Err(err) = clear_pending() {
+            println!("ESRT: failed to clear pending capsule: {:?}", err);
+        }
+    }
+
+    log
+}
+
+fn set_pending(data: &[u8]) -> Result<()> {
+    let uefi = std::system_table();
+
+    let wname = wstr(PENDING_NAME);
+    Result::from((uefi.RuntimeServices.SetVariable)(
+        wname.as_ptr(),
+        &PENDING_GUID,
+        VARIABLE_NON_VOLATILE | VARIABLE_BOOTSERVICE_ACCESS | VARIABLE_RUNTIME_ACCESS,
+        data.len(),
+        data.as_ptr(),
+    ))?;
+
+    Ok(())
+}
+
+/// Remember the resources a submitted capsule updates, so the result is reported on next boot
+pub fn save_pending(classes: &[Guid]) -> Result<()> {
+    let mut data = String::new();
+    for class in classes.iter() {
+        let _ = writeln!(data, "{}", guid_string(class));
+    }
+    set_pending(data.as_bytes())
+}
+
+/// A submitted capsule has not had its result reported yet
+pub fn pending() -> bool {
+    load_pending().is_some_and(|classes| !classes.is_empty())
+}
+
+fn load_pending() -> Option<Vec<Guid>> {
+    let uefi = std::system_table();
+
+    let wname = wstr(PENDING_NAME);
+    let mut attributes = 0;
+    let mut data = [0; 1024];
+    let mut data_size = data.len();
+    let status = (uefi.RuntimeServices.GetVariable)(
+        wname.as_ptr(),
+        &PENDING_GUID,
+        &mut attributes,
+        &mut data_size,
+        data.as_mut_ptr(),
+    );
+    if !status.is_success() {
+        return None;
+    }
+
+    let text = str::from_utf8(&data[..data_size]).ok()?;
+    Some(text.lines().filter_map(parse_guid).collect())
+}
+
+fn clear_pending() -> Result<()> {
+    match set_pending(&[]) {
+        Ok(()) | Err(Status::NOT_FOUND) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
@@ -0,0 +1,201 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use alloc::string::String;
+use core::{mem, ptr};
+use sha2::{Digest, Sha256};
+use std::ffi::wstr;
+use std::fs::{find, load};
+use std::prelude::*;
+use std::uefi;
+
+/// Open a file on the volume containing its parent directory, creating it if it does not exist
+fn create(path: &str, attributes: u64) -> Result<&'static mut uefi::fs::File> {
+    let parent = match path.rfind('\\') {
+        Some(i) if i > 0 => &path[..i],
+        _ => return Err(Status::INVALID_PARAMETER),
+    };
+    let (_, dir) = find(parent)?;
+
+    let filename = wstr(path);
+    let mut file = ptr::null_mut::<uefi::fs::File>();
+    Result::from((dir.0.Open)(
+        dir.0,
+        &mut file,
+        filename.as_ptr(),
+        uefi::fs::FILE_MODE_CREATE | uefi::fs::FILE_MODE_READ | uefi::fs::FILE_MODE_WRITE,
+        attributes,
+    ))?;
+
+    Ok(unsafe { &mut *file })
+}
+
+/// Create a directory, succeeding if it already exists
+pub fn create_dir(path: &str) -> Result<()> {
+    let dir = create(path, uefi::fs::FILE_DIRECTORY)?;
+    Result::from((dir.Close)(dir))
+}
+
+/// Write data to a file, replacing it if it already exists
+pub fn save(path: &str, data: &[u8]) -> Result<()> {
+    delete(path)?;
+    let file = create(path, 0)?;
+
+    let mut written = 0;
+    let mut status = Status::SUCCESS;
+    while written < data.len() {
+        let mut size = data.len() - written;
+        status = (file.Write)(file, &mut size, data[written..].as_ptr());
+        if status.is_error() || size == 0 {
+            break;
+        }
+        written += size;
+    }
+
+    if !status.is_error() {
+        status = (file.Flush)(file);
+    }
+
+    let _ = (file.Close)(file);
+
+    if status.is_error() {
+        return Err(status);
+    }
+
+    if written != data.len() {
+        return Err(Status::VOLUME_FULL);
+    }
+
+    Ok(())
+}
+
+/// Lowercase hex SHA-256 of data
+pub fn sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Delete a file if it exists, as opening an existing file with create does not truncate it
+pub fn delete(path: &str) -> Result<()> {
+    if let Ok((_, file)) = find(path) {
+        let status = (file.0.Delete)(file.0);
+        // Have to prevent Close from being called after Delete
+        mem::forget(file);
+        if status.is_error() {
+            return Err(status);
+        }
+    }
+    Ok(())
+}
+
+/// Lowercase hex SHA-256 of a file, read in chunks
+pub fn sha256_file(path: &str) -> Result<String> {
+    let (_, file) = find(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let mut size = buf.len();
+        Result::from((file.0.Read)(file.0, &mut size, buf.as_mut_ptr()))?;
+        if size == 0 {
+            break;
+        }
+        hasher.update(&buf[..size]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Writes a file in chunks, then a `.sha256` sidecar in sha256sum format, reading both back to
+/// verify them
+pub struct VerifiedWriter {
+    path: String,
+    file: Option<&'static mut uefi::fs::File>,
+    hasher: Sha256,
+}
+
+impl VerifiedWriter {
+    /// Create the file, replacing it if it already exists
+    pub fn create(path: &str) -> Result<Self> {
+        delete(path)?;
+        let file = create(path, 0)?;
+        Ok(Self {
+            path: path.to_string(),
+            file: Some(file),
+            hasher: Sha256::new(),
+        })
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        let file = self.file.as_deref_mut().ok_or(Status::INVALID_PARAMETER)?;
+        let mut written = 0;
+        while written < data.len() {
+            let mut size = data.len() - written;
+            Result::from((file.Write)(file, &mut size, data[written..].as_ptr()))?;
+            if size == 0 {
+                return Err(Status::VOLUME_FULL);
+            }
+            written += size;
+        }
+        self.hasher.update(data);
+        Ok(())
+    }
+
+    /// Close the file, write the sidecar, and verify both, returning the hash
+    pub fn finish(mut self) -> Result<String> {
+        let file = self.file.take().ok_or(Status::INVALID_PARAMETER)?;
+        let status = (file.Flush)(file);
+        let _ = (file.Close)(file);
+        if status.is_error() {
+            return Err(status);
+        }
+
+        let hash = format!("{:x}", self.hasher.clone().finalize());
+        let name = self.path.rsplit('\\').next().unwrap_or(&self.path);
+        let sidecar_path = format!("{}.sha256", self.path);
+        let sidecar = format!("{}  {}\n", hash, name);
+        save(&sidecar_path, sidecar.as_bytes())?;
+
+        if sha256_file(&self.path)? != hash {
+            println!("{}: verification failed", self.path);
+            return Err(Status::VOLUME_CORRUPTED);
+        }
+
+        if load(&sidecar_path)? != sidecar.as_bytes() {
+            println!("{}: verification failed", sidecar_path);
+            return Err(Status::VOLUME_CORRUPTED);
+        }
+
+        Ok(hash)
+    }
+}
+
+impl Drop for VerifiedWriter {
+    fn drop(&mut self) {
+        if let Some(file) = self.file.take() {
+            let _ = (file.Close)(file);
+        }
+    }
+}
+
+/// Replace characters that are not safe in file names
+pub fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Current time from the firmware clock, formatted for use in file names
+pub fn timestamp() -> String {
+    let mut time = uefi::time::Time::default();
+    let status = (std::system_table().RuntimeServices.GetTime)(&mut time, ptr::null_mut());
+    if status.is_error() {
+        return "unknown-time".to_string();
+    }
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        time.Year, time.Month, time.Day, time.Hour, time.Minute, time.Second
+    )
+}
@@ -0,0 +1,69 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use core::str;
+use coreboot_fs::Rom;
+use std::fs::load;
+use std::prelude::*;
+
+pub use system76_firmware_update_logic::fmap::*;
+
+use super::{FMAPPOLICY, PARTIALLIST};
+
+fn fmap_name(bytes: &[u8]) -> String {
+    let mut name = String::new();
+    for &b in bytes.iter() {
+        if b == 0 {
+            break;
+        }
+        name.push(b as char);
+    }
+    name
+}
+
+/// Parse the FMAP of a coreboot ROM, if it has one
+pub fn new(data: &[u8]) -> Option<Fmap> {
+    let rom = Rom::new(data);
+    let fmap = rom.fmap()?;
+
+    let mut areas = BTreeMap::new();
+    for i in 0..fmap.nareas {
+        let area = fmap.area(i);
+        areas.insert(
+            fmap_name(&area.name),
+            Area {
+                offset: area.offset as usize,
+                size: area.size as usize,
+            },
+        );
+    }
+
+    Some(Fmap {
+        name: fmap_name(&fmap.name),
+        areas,
+    })
+}
+
+/// Load the partial update allowlist from the firmware bundle, if it has one
+pub fn load_allowlist() -> Result<Option<Allowlist>> {
+    let data = match load(PARTIALLIST) {
+        Ok(data) => data,
+        Err(_) => return Ok(None),
+    };
+    let text = str::from_utf8(&data).map_err(|_| Status::LOAD_ERROR)?;
+    Ok(Some(Allowlist::parse(text)))
+}
+
+/// Load the default FMAP policy, with overrides from the firmware bundle
+pub fn load_policy() -> Result<AreaPolicy> {
+    let mut policy = AreaPolicy::default();
+    if let Ok(data) = load(FMAPPOLICY) {
+        let text = str::from_utf8(&data).map_err(|_| Status::LOAD_ERROR)?;
+        policy.parse(text).map_err(|err| {
+            println!("FMAP policy: {}", err);
+            Status::LOAD_ERROR
+        })?;
+    }
+    Ok(policy)
+}
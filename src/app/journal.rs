@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use alloc::string::String;
+use core::fmt::Write;
+use core::str;
+use std::fs::load;
+use std::prelude::*;
+
+use super::sector::Sector;
+use super::{JOURNAL, JOURNALNEXT, JOURNALROM, file};
+
+// Progress of an interrupted SPI flash is kept in files on the ESP. UEFI variables are stored
+// in the SPI flash being written, so they cannot be used while flashing.
+
+pub struct Journal {
+    /// SHA-256 of the firmware image from the bundle
+    pub hash: String,
+    /// SHA-256 of the image being written, with areas of the old firmware copied in
+    pub target: String,
+    /// Sectors to write, in order
+    pub sectors: Vec<Sector>,
+    /// Index of the first sector written last to keep the old firmware bootable
+    pub critical: usize,
+    /// Index in the write order of the first sector that has not been verified
+    pub next: usize,
+}
+
+impl Journal {
+    /// Save the image being written and the sectors to write, before anything is erased
+    pub fn start(hash: String, new: &[u8], sectors: &[Sector], critical: usize) -> Result<Self> {
+        let mut writer = file::VerifiedWriter::create(JOURNALROM)?;
+        writer.write(new)?;
+        let target = writer.finish()?;
+
+        let mut text = String::new();
+        let _ = writeln!(text, "{}", hash);
+        let _ = writeln!(text, "{}", target);
+        let _ = writeln!(text, "{}", critical);
+        for sector in sectors.iter() {
+            let _ = writeln!(
+                text,
+                "{:X} {} {}",
+                sector.address, sector.erase as u8, sector.write as u8
+            );
+        }
+        file::save(JOURNAL, text.as_bytes())?;
+
+        let journal = Self {
+            hash,
+            target,
+            sectors: sectors.to_vec(),
+            critical,
+            next: 0,
+        };
+        journal.save()?;
+        Ok(journal)
+    }
+
+    /// Read the journal left by an interrupted flash, if there is one
+    pub fn load() -> Option<Self> {
+        let data = load(JOURNAL).ok()?;
+        let text = str::from_utf8(&data).ok()?;
+        let mut lines = text.lines();
+        let hash = lines.next()?.to_string();
+        let target = lines.next()?.to_string();
+        let critical = lines.next()?.parse().ok()?;
+        let mut sectors = Vec::new();
+        for line in lines {
+            let mut parts = line.split_whitespace();
+            sectors.push(Sector {
+                address: usize::from_str_radix(parts.next()?, 16).ok()?,
+                erase: parts.next()? == "1",
+                write: parts.next()? == "1",
+            });
+        }
+
+        // Without saved progress, every sector is written again
+        let next = load(JOURNALNEXT)
+            .ok()
+            .and_then(|data| str::from_utf8(&data).ok()?.trim().parse().ok())
+            .unwrap_or(0)
+            .min(sectors.len());
+
+        Some(Self {
+            hash,
+            target,
+            sectors,
+            critical,
+            next,
+        })
+    }
+
+    /// Load the image that was being written, checking that it was saved completely
+    pub fn image(&self) -> Result<Vec<u8>> {
+        let data = load(JOURNALROM)?;
+        if file::sha256(&data) != self.target {
+            println!("SPI JOURNAL: {} does not match the journal", JOURNALROM);
+            return Err(Status::VOLUME_CORRUPTED);
+        }
+        Ok(data)
+    }
+
+    /// Record progress
+    pub fn save(&self) -> Result<()> {
+        file::save(JOURNALNEXT, format!("{}\n", self.next).as_bytes())
+    }
+
+    /// Remove the journal once the flash is complete and verified
+    pub fn clear() -> Result<()> {
+        for path in [
+            JOURNAL,
+            JOURNALNEXT,
+            JOURNALROM,
+            &format!("{}.sha256", JOURNALROM),
+        ] {
+            file::delete(path)?;
+        }
+        Ok(())
+    }
+}
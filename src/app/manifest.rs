@@ -0,0 +1,127 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use core::str;
+use std::fs::{find, load};
+use std::prelude::*;
+
+use super::{
+    ALLOWDOWNGRADE, ALLOWUNKNOWNBOARD, DRYRUN, EC2ROM, ECBACKUPPREVIOUS, ECMODELS, ECROM,
+    FIRMWARECAP, FIRMWAREDIR, FIRMWARERESTORE, FIRMWAREROM, FMAPPOLICY, H2OFFT, IFLASHV, IPXEEFI,
+    MANIFEST, PARTIALLIST, UECFLASH, UEFIFLASH, file,
+};
+
+/// Files in the firmware directory that are only used by the firmware.nsh script
+static SCRIPT_FILES: &[&str] = &[
+    "ec.nsh",
+    "firmware.nsh",
+    "fparts.txt",
+    "fpt.efi",
+    "meset.efi",
+];
+
+/// Verify the bundle manifest, which is required. The manifest has a `model <name>` line, and a
+/// `<sha256>  <file>` line for each file in the firmware directory, in sha256sum format.
+pub fn verify(system_version: &str) -> Result<()> {
+    let data = match load(MANIFEST) {
+        Ok(ok) => ok,
+        Err(err) => {
+            if find(MANIFEST).is_ok() {
+                println!("Manifest: failed to load: {:?}", err);
+            } else {
+                println!("Manifest: not found");
+            }
+            return Err(err);
+        }
+    };
+    let text = str::from_utf8(&data).map_err(|_| Status::LOAD_ERROR)?;
+
+    let mut model = None;
+    let mut entries = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix("model ") {
+            model = Some(name.trim());
+        } else if let Some((hash, name)) = line.split_once(char::is_whitespace) {
+            // sha256sum marks binary files with '*'
+            let name = name.trim_start().trim_start_matches('*');
+            entries.push((hash.to_ascii_lowercase(), name));
+        } else {
+            println!("Manifest: invalid line {}: {:?}", i + 1, line);
+            return Err(Status::LOAD_ERROR);
+        }
+    }
+
+    match model {
+        Some(model) if model == system_version => (),
+        Some(model) => {
+            println!(
+                "Manifest: bundle is for {}, but this system is {}",
+                model, system_version
+            );
+            return Err(Status::INCOMPATIBLE_VERSION);
+        }
+        None => {
+            println!("Manifest: missing model");
+            return Err(Status::LOAD_ERROR);
+        }
+    }
+
+    for (hash, name) in &entries {
+        let path = format!("{}\\{}", FIRMWAREDIR, name);
+        let file_hash = match file::sha256_file(&path) {
+            Ok(ok) => ok,
+            Err(err) => {
+                println!("Manifest: {}: failed to load: {:?}", name, err);
+                return Err(err);
+            }
+        };
+
+        if file_hash != *hash {
+            println!("Manifest: {}: SHA-256 mismatch", name);
+            return Err(Status::CRC_ERROR);
+        }
+
+        println!("Manifest: {}: OK", name);
+    }
+
+    // Every file that is flashed, run, or changes what is flashed must be covered
+    let paths = [
+        ALLOWDOWNGRADE,
+        ALLOWUNKNOWNBOARD,
+        DRYRUN,
+        ECBACKUPPREVIOUS,
+        ECMODELS,
+        ECROM,
+        EC2ROM,
+        FIRMWARECAP,
+        FIRMWARERESTORE,
+        FIRMWAREROM,
+        FMAPPOLICY,
+        H2OFFT,
+        IFLASHV,
+        IPXEEFI,
+        PARTIALLIST,
+        UECFLASH,
+        UEFIFLASH,
+    ]
+    .into_iter()
+    .map(|path| path.to_string())
+    .chain(
+        SCRIPT_FILES
+            .iter()
+            .map(|name| format!("{}\\{}", FIRMWAREDIR, name)),
+    );
+    for path in paths {
+        let name = path.rsplit('\\').next().unwrap_or(&path);
+        if find(&path).is_ok() && !entries.iter().any(|(_, entry)| *entry == name) {
+            println!("Manifest: {}: not listed", name);
+            return Err(Status::SECURITY_VIOLATION);
+        }
+    }
+
+    Ok(())
+}
@@ -12,6 +12,7 @@ use std::vars::{
     get_boot_current, get_boot_item, get_boot_next, get_boot_order, set_boot_item, set_boot_next,
     set_boot_order,
 };
+use system76_firmware_update_logic::version;
 
 use crate::display::{Display, Output, ScaledDisplay};
 use crate::image::{self, Image};
@@ -24,31 +25,74 @@ pub use self::ec::{EcComponent, EcKind};
 pub use self::mapper::UefiMapper;
 pub use self::pci::{pci_mcfg, pci_read};
 
+mod alias;
 mod bios;
+mod capsule;
+mod cbfs;
 mod cmos;
 mod component;
+mod descriptor;
 mod ec;
+mod erase;
+mod esrt;
+mod file;
+mod fmap;
+mod journal;
+mod manifest;
 mod mapper;
 mod pci;
+mod protection;
+mod sector;
 mod sideband;
-
+mod signature;
+
+static ALLOWDOWNGRADE: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\allow-downgrade");
+static ALLOWUNKNOWNBOARD: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\allow-unknown-board");
+static BACKUPDIR: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\backup");
+static DRYRUN: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\dry-run");
+static DUMP: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\dump");
+static DUMPDIR: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\dumps");
+static ECBACKUPPREVIOUS: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\ec-backup-previous");
+static ECMODELS: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\ec-models.txt");
 static ECROM: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\ec.rom");
 static ECTAG: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\ec.tag");
 static EC2ROM: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\ec2.rom");
+static ESRTLOG: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\esrt.log");
 static FIRMWAREDIR: &str = concat!("\\", env!("BASEDIR"), "\\firmware");
 static FIRMWARENSH: &str = concat!("\\", env!("BASEDIR"), "\\res\\firmware.nsh");
 static FIRMWARECAP: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\firmware.cap");
+static FIRMWARERESTORE: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\restore.rom");
 static FIRMWAREROM: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\firmware.rom");
+static FMAPLOG: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\fmap.log");
+static FMAPPOLICY: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\fmap.txt");
 static H2OFFT: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\h2offt.efi");
 static IFLASHV: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\iflashv.efi");
 static IFLASHVTAG: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\iflashv.tag");
 static IPXEEFI: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\ipxe.efi");
+static JOURNAL: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\journal.txt");
+static JOURNALNEXT: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\journal.next");
+static JOURNALROM: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\journal.rom");
+static MANIFEST: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\manifest.txt");
 static MESETTAG: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\meset.tag");
+static PARTIALLIST: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\partial.txt");
 static SHELLEFI: &str = concat!("\\", env!("BASEDIR"), "\\res\\shell.efi");
 static SPLASHBMP: &str = concat!("\\", env!("BASEDIR"), "\\res\\splash.bmp");
+static TIMINGLOG: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\timing.log");
+static UECFLASH: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\uecflash.efi");
 static UEFIFLASH: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\uefiflash.efi");
 static UEFIFLASHTAG: &str = concat!("\\", env!("BASEDIR"), "\\firmware\\uefiflash.tag");
 
+/// Ask the person at the machine to confirm flashing an image that is not signed, as a key press
+/// cannot be made by software that can only write files to the ESP
+fn confirm_unsigned(path: &str, hash: &str) -> Result<bool> {
+    println!("{} is not signed", path);
+    println!("SHA-256 {}", hash);
+    println!("Press U to flash it anyway, or any other key to cancel...");
+    let k = raw_key()?;
+    let c = unsafe { char::from_u32_unchecked(k.UnicodeChar as u32) };
+    Ok(c == 'u' || c == 'U')
+}
+
 fn shell(cmd: &str) -> Result<usize> {
     exec_path(
         SHELLEFI,
@@ -59,6 +103,8 @@ fn shell(cmd: &str) -> Result<usize> {
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 enum ValidateKind {
     Found,
+    Downgrade,
+    BadSignature,
     Mismatch,
     NotFound,
     Error(Status),
@@ -89,6 +135,8 @@ fn components_validations() -> (Vec<Box<dyn Component>>, Vec<ValidateKind>) {
                 Err(err) => {
                     if err == Status::NOT_FOUND || err == Status::INVALID_PARAMETER {
                         ValidateKind::NotFound
+                    } else if err == Status::SECURITY_VIOLATION {
+                        ValidateKind::BadSignature
                     } else {
                         ValidateKind::Error(err)
                     }
@@ -99,6 +147,22 @@ fn components_validations() -> (Vec<Box<dyn Component>>, Vec<ValidateKind>) {
                 print!("\x08");
             }
 
+            // Refuse downgrades unless explicitly allowed. Restoring a backup is expected to
+            // go back to an older version, so it is not checked.
+            let ret = if ret == ValidateKind::Found
+                && !component.restore()
+                && version::is_downgrade(component.version(), &component.new_version())
+            {
+                if find(ALLOWDOWNGRADE).is_ok() {
+                    println!("Downgrade allowed by allow-downgrade");
+                    ret
+                } else {
+                    ValidateKind::Downgrade
+                }
+            } else {
+                ret
+            };
+
             if ret == ValidateKind::NotFound {
                 print!("\x08\x08");
                 for _c in component.name().chars() {
@@ -108,8 +172,23 @@ fn components_validations() -> (Vec<Box<dyn Component>>, Vec<ValidateKind>) {
                 println!("{:?}", ret);
 
                 let current_version = component.version();
-                if !current_version.is_empty() {
+                let new_version = component.new_version();
+                if !current_version.is_empty() && !new_version.is_empty() {
+                    println!(
+                        "{}: Currently {} → New {}{}",
+                        component.name(),
+                        current_version,
+                        new_version,
+                        if new_version == current_version {
+                            " (unchanged)"
+                        } else {
+                            ""
+                        }
+                    );
+                } else if !current_version.is_empty() {
                     println!("{}: Currently {}", component.name(), current_version);
+                } else if !new_version.is_empty() {
+                    println!("{}: New {}", component.name(), new_version);
                 }
             }
 
@@ -215,12 +294,61 @@ fn remove_override(option: u16) -> Result<()> {
 fn inner() -> Result<()> {
     let mut reboot = false;
     let mut success = false;
+    let mut keep_override = false;
 
     let option = set_override()?;
 
+    // A capsule submitted on the last run has been processed by the reset since
+    let capsule_reported = esrt::pending();
+    let esrt_log = esrt::report();
+    if let Err(err) = file::save(ESRTLOG, esrt_log.as_bytes()) {
+        println!("Failed to write {}: {:?}", ESRTLOG, err);
+    }
+
     let (mut components, mut validations) = components_validations();
 
-    let message = if validations
+    let manifest_valid = manifest::verify(&crate::dmi::system_version()).is_ok();
+
+    let mut downgrade_refused = false;
+    if manifest_valid && validations.iter().any(|v| *v == ValidateKind::Downgrade) {
+        println!("Press D to downgrade firmware, or any other key to cancel...");
+        let k = raw_key()?;
+        let c = unsafe { char::from_u32_unchecked(k.UnicodeChar as u32) };
+        if c == 'd' || c == 'D' {
+            for validation in validations.iter_mut() {
+                if *validation == ValidateKind::Downgrade {
+                    *validation = ValidateKind::Found;
+                }
+            }
+        } else {
+            downgrade_refused = true;
+        }
+    }
+
+    let message = if capsule_reported {
+        // Report the capsule result once, instead of applying the bundle again
+        "* Capsule update result recorded in esrt.log *"
+    } else if find(DUMP).is_ok() {
+        // Read firmware without flashing if requested by the bundle, which does not need images
+        let mut dumped = true;
+
+        for component in components.iter() {
+            if let Err(err) = component.dump() {
+                println!("{}: Failure: {:?}", component.name(), err);
+                dumped = false;
+            }
+        }
+
+        if dumped {
+            "* Dump complete, nothing was flashed *"
+        } else {
+            "! Dump failed, nothing was flashed !"
+        }
+    } else if !manifest_valid {
+        "! Bundle manifest verification failed !"
+    } else if downgrade_refused {
+        "! Not applying downgrade !"
+    } else if validations
         .iter()
         .any(|v| *v != ValidateKind::Found && *v != ValidateKind::NotFound)
     {
@@ -228,7 +356,10 @@ fn inner() -> Result<()> {
     } else if !validations.iter().any(|v| *v == ValidateKind::Found) {
         "* No updates were found *"
     } else {
-        let c = if let Ok((_, ectag)) = find(ECTAG) {
+        let c = if find(DRYRUN).is_ok() {
+            // Plan without flashing if requested by the bundle
+            'p'
+        } else if let Ok((_, ectag)) = find(ECTAG) {
             // Attempt to remove EC tag
             let status = (ectag.0.Delete)(ectag.0);
             // XXX: Match previous behavior, which ignored warnings.
@@ -264,11 +395,29 @@ fn inner() -> Result<()> {
             }
         } else {
             println!("Press enter to commence flashing, the system may reboot...");
+            println!("Press P to show what would be flashed, without flashing...");
             let k = raw_key()?;
             unsafe { char::from_u32_unchecked(k.UnicodeChar as u32) }
         };
 
-        if c == '\n' || c == '\r' {
+        if c == 'p' || c == 'P' {
+            let mut planned = true;
+
+            for (component, validation) in components.iter().zip(validations.iter()) {
+                if *validation == ValidateKind::Found {
+                    if let Err(err) = component.dry_run() {
+                        println!("{}: Failure: {:?}", component.name(), err);
+                        planned = false;
+                    }
+                }
+            }
+
+            if planned {
+                "* Dry run complete, nothing was flashed *"
+            } else {
+                "! Dry run failed, nothing was flashed !"
+            }
+        } else if c == '\n' || c == '\r' {
             success = true;
 
             for c in &components {
@@ -330,6 +479,10 @@ fn inner() -> Result<()> {
                         }
                         Err(err) => {
                             println!("{}: Failure: {:?}", component.name(), err);
+                            // Flash was interrupted, so run the updater again on the next boot
+                            if err == Status::ABORTED {
+                                keep_override = true;
+                            }
                             success = false;
                             break;
                         }
@@ -337,6 +490,11 @@ fn inner() -> Result<()> {
                 }
             }
 
+            // Run the updater again after the capsule reset, so the result is recorded
+            if success && esrt::pending() {
+                keep_override = true;
+            }
+
             if success {
                 if find(IFLASHV).is_ok() {
                     // Do not reset DMI on meer5
@@ -354,10 +512,17 @@ fn inner() -> Result<()> {
         }
     };
 
-    remove_override(option)?;
+    if keep_override {
+        println!("Keeping boot override {:>04X} for the next run", option);
+    } else {
+        remove_override(option)?;
+    }
 
     println!("{}", message);
 
+    // Dumping a legacy EC leaves it in the scratch ROM, which is only left by a reset
+    ec::dump_reset();
+
     if success && find(IPXEEFI).is_ok() {
         println!("Launching iPXE...");
         match exec_path(IPXEEFI, &[]) {
@@ -370,7 +535,12 @@ fn inner() -> Result<()> {
         }
     }
 
-    if find(H2OFFT).is_ok() {
+    if let Some(reset_type) = capsule::pending_reset() {
+        // Capsule is in memory, so the reset type requested by firmware must be used
+        println!("System will reboot in 5 seconds to perform capsule update");
+        let _ = (std::system_table().BootServices.Stall)(5_000_000);
+        (std::system_table().RuntimeServices.ResetSystem)(reset_type, Status(0), 0, ptr::null());
+    } else if find(H2OFFT).is_ok() {
         // H2OFFT will automatically shut down, so skip success confirmation
         println!("System will reboot in 5 seconds to perform capsule update");
         let _ = (std::system_table().BootServices.Stall)(5_000_000);
@@ -0,0 +1,221 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use core::ptr;
+use intel_spi::HsfStsCtl;
+use std::prelude::*;
+
+use super::descriptor::REGION_NAMES;
+use super::pci_read;
+use super::sector::Plan;
+
+// SPI controller is at 00:1f.5
+const SPI_DEV: u8 = 0x1f;
+const SPI_FUNC: u8 = 0x5;
+const SPI_BAR0: u8 = 0x10;
+const SPI_BIOS_CNTL: u8 = 0xDC;
+
+const BIOS_CNTL_BIOSWE: u32 = 1 << 0;
+const BIOS_CNTL_BLE: u32 = 1 << 1;
+const BIOS_CNTL_SMM_BWP: u32 = 1 << 5;
+
+// SPI BAR registers
+const SPIBAR_FRACC: usize = 0x50;
+const SPIBAR_FREG: usize = 0x54;
+const SPIBAR_FPR: usize = 0x84;
+
+const FPR_COUNT: usize = 5;
+const FPR_WPE: u32 = 1 << 31;
+
+// Regions covered by FREG registers
+const FREG_COUNT: usize = 6;
+
+pub struct Region {
+    pub name: &'static str,
+    pub base: usize,
+    pub limit: usize,
+    pub writable: bool,
+}
+
+pub struct ProtectedRange {
+    pub index: usize,
+    pub base: usize,
+    pub limit: usize,
+}
+
+/// Write protection state of the Intel SPI controller
+pub struct SpiProtection {
+    pub flockdn: bool,
+    pub fdopss: bool,
+    pub bios_cntl: u32,
+    pub regions: Vec<Region>,
+    pub ranges: Vec<ProtectedRange>,
+}
+
+/// Physical address of the SPI controller registers
+pub fn spi_bar() -> Option<usize> {
+    let vendor_device = pci_read(0x00, SPI_DEV, SPI_FUNC, 0x00).ok()?;
+    if vendor_device & 0xFFFF != 0x8086 {
+        return None;
+    }
+
+    let bar = (pci_read(0x00, SPI_DEV, SPI_FUNC, SPI_BAR0).ok()? & 0xFFFF_F000) as usize;
+    if bar == 0 {
+        return None;
+    }
+    Some(bar)
+}
+
+impl SpiProtection {
+    pub fn new(hsfsts_ctl: HsfStsCtl) -> Option<Self> {
+        let bar = spi_bar()?;
+        let bios_cntl = pci_read(0x00, SPI_DEV, SPI_FUNC, SPI_BIOS_CNTL).ok()? & 0xFF;
+
+        // On UEFI, physical memory is identity mapped
+        let read =
+            |offset: usize| -> u32 { unsafe { ptr::read_volatile((bar + offset) as *const u32) } };
+
+        // BIOS master write access bits
+        let brwa = (read(SPIBAR_FRACC) >> 8) & 0xFF;
+
+        let mut regions = Vec::new();
+        for (i, name) in REGION_NAMES.iter().copied().enumerate().take(FREG_COUNT) {
+            let freg = read(SPIBAR_FREG + i * 4);
+            let base = ((freg & 0x7FFF) as usize) << 12;
+            let limit = ((((freg >> 16) & 0x7FFF) as usize) << 12) | 0xFFF;
+            // Unused regions have a base above the limit
+            if base < limit {
+                regions.push(Region {
+                    name,
+                    base,
+                    limit,
+                    writable: brwa & (1 << i) != 0,
+                });
+            }
+        }
+
+        let mut ranges = Vec::new();
+        for i in 0..FPR_COUNT {
+            let fpr = read(SPIBAR_FPR + i * 4);
+            if fpr & FPR_WPE != 0 {
+                ranges.push(ProtectedRange {
+                    index: i,
+                    base: ((fpr & 0x7FFF) as usize) << 12,
+                    limit: ((((fpr >> 16) & 0x7FFF) as usize) << 12) | 0xFFF,
+                });
+            }
+        }
+
+        Some(Self {
+            flockdn: hsfsts_ctl.contains(HsfStsCtl::FLOCKDN),
+            fdopss: hsfsts_ctl.contains(HsfStsCtl::FDOPSS),
+            bios_cntl,
+            regions,
+            ranges,
+        })
+    }
+
+    /// BIOS_CNTL only allows writes from SMM
+    fn bios_cntl_blocked(&self) -> bool {
+        self.bios_cntl & BIOS_CNTL_SMM_BWP != 0
+            || (self.bios_cntl & BIOS_CNTL_BLE != 0 && self.bios_cntl & BIOS_CNTL_BIOSWE == 0)
+    }
+
+    pub fn print(&self) {
+        println!(
+            "SPI LOCK: FLOCKDN {}, descriptor override {}, BIOS_CNTL {:#04X}",
+            if self.flockdn { "set" } else { "clear" },
+            if self.fdopss { "inactive" } else { "active" },
+            self.bios_cntl
+        );
+        for region in self.regions.iter() {
+            let mut reasons = Vec::new();
+            if !region.writable {
+                reasons.push("no host write access".to_string());
+            }
+            for range in self.ranges.iter() {
+                if range.base <= region.limit && region.base <= range.limit {
+                    reasons.push(format!("PR{}", range.index));
+                }
+            }
+            if region.name == "BIOS" && self.bios_cntl_blocked() {
+                reasons.push("BIOS_CNTL SMM_BWP/BLE".to_string());
+            }
+            println!(
+                "SPI LOCK: {} {:#08X}:{:#08X}: {}",
+                region.name,
+                region.base,
+                region.limit,
+                if reasons.is_empty() {
+                    "writable".to_string()
+                } else {
+                    format!("protected by {}", reasons.join(", "))
+                }
+            );
+        }
+    }
+
+    /// Reason writing the BIOS region is impossible, regardless of which sectors change
+    pub fn bios_blocker(&self) -> Option<String> {
+        if self.bios_cntl_blocked() {
+            return Some(format!(
+                "BIOS_CNTL {:#04X} only allows writes from SMM",
+                self.bios_cntl
+            ));
+        }
+        match self.regions.iter().find(|region| region.name == "BIOS") {
+            Some(region) if !region.writable => {
+                Some("BIOS region is not writable by the host".to_string())
+            }
+            _ => None,
+        }
+    }
+
+    /// Check every sector in the plan, printing why each blocked range cannot be written
+    pub fn check(&self, plan: &Plan) -> bool {
+        let mut allowed = true;
+
+        if let Some(blocker) = self.bios_blocker() {
+            println!("SPI LOCK: {}", blocker);
+            allowed = false;
+        }
+
+        let mut last = None;
+        for sector in plan.sectors.iter() {
+            let start = sector.address;
+            let end = start + plan.sector_size - 1;
+
+            let mut reason = None;
+            for range in self.ranges.iter() {
+                if range.base <= end && start <= range.limit {
+                    reason = Some(format!(
+                        "PR{} {:#08X}:{:#08X} is write protected",
+                        range.index, range.base, range.limit
+                    ));
+                    break;
+                }
+            }
+            if reason.is_none() {
+                for region in self.regions.iter() {
+                    if !region.writable && region.base <= end && start <= region.limit {
+                        reason = Some(format!(
+                            "{} region {:#08X}:{:#08X} is not writable by the host",
+                            region.name, region.base, region.limit
+                        ));
+                        break;
+                    }
+                }
+            }
+
+            if let Some(reason) = reason {
+                // Only print each reason once for consecutive sectors
+                if last.as_ref() != Some(&reason) {
+                    println!("SPI LOCK: {:#08X}: {}", start, reason);
+                }
+                last = Some(reason);
+                allowed = false;
+            }
+        }
+
+        allowed
+    }
+}
@@ -0,0 +1,49 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use alloc::string::String;
+use std::prelude::*;
+
+pub use system76_firmware_update_logic::sector::*;
+
+/// Compare the new contents against the chip one sector at a time, printing progress
+pub fn read_plan(
+    old: &mut dyn RomReader,
+    new: &[u8],
+    sector_size: usize,
+    name: &str,
+) -> core::result::Result<Plan, String> {
+    let mut print_mb = !0; // Invalid number to force first print
+    let plan = Plan::read(old, new, sector_size, &mut |address| {
+        // Print output once per megabyte
+        let mb = address / (1024 * 1024);
+        if mb != print_mb {
+            print!("\r{} COMPARE: {} MB", name, mb);
+            print_mb = mb;
+        }
+    });
+    println!();
+    plan
+}
+
+/// Measures elapsed time with the TSC, calibrated against the UEFI stall service
+pub struct Stopwatch {
+    start: u64,
+    ticks_per_ms: u64,
+}
+
+impl Stopwatch {
+    pub fn start() -> Self {
+        let before = unsafe { core::arch::x86_64::_rdtsc() };
+        let _ = (std::system_table().BootServices.Stall)(10_000);
+        let after = unsafe { core::arch::x86_64::_rdtsc() };
+        Self {
+            start: after,
+            ticks_per_ms: ((after - before) / 10).max(1),
+        }
+    }
+
+    pub fn elapsed_ms(&self) -> u64 {
+        let now = unsafe { core::arch::x86_64::_rdtsc() };
+        (now - self.start) / self.ticks_per_ms
+    }
+}
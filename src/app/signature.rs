@@ -0,0 +1,41 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::fs::load;
+use std::prelude::*;
+use system76_firmware_update_logic::signature::{self, Error};
+
+/// Ed25519 public keys trusted to sign firmware images, as concatenated 32 byte raw keys
+const PUBLIC_KEYS: &[u8] = include_bytes!("../../keys/firmware.pub");
+
+// A build without keys would refuse every image, so it is not allowed
+const _: () = assert!(
+    signature::keys_valid(PUBLIC_KEYS),
+    "keys/firmware.pub must hold one or more raw 32 byte Ed25519 public keys"
+);
+
+/// Verify the detached signature of a firmware image, stored next to it with a `.sig` extension
+pub fn verify(path: &str, data: &[u8]) -> Result<()> {
+    let sig_path = format!("{}.sig", path);
+    let sig_data = match load(&sig_path) {
+        Ok(ok) => ok,
+        Err(err) => {
+            println!("\n{}: failed to load signature: {:?}", sig_path, err);
+            return Err(Status::SECURITY_VIOLATION);
+        }
+    };
+
+    match signature::verify(PUBLIC_KEYS, data, &sig_data) {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            match err {
+                Error::NoKeys => println!("\n{}: no trusted keys in this build", path),
+                Error::InvalidKeys => println!("\n{}: trusted keys are invalid", path),
+                Error::InvalidSignature => println!("\n{}: invalid signature", sig_path),
+                Error::Mismatch => {
+                    println!("\n{}: signature does not match any trusted key", path)
+                }
+            }
+            Err(Status::SECURITY_VIOLATION)
+        }
+    }
+}
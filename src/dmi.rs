@@ -1,6 +1,7 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 use core::slice;
+use plain::Plain;
 use std::prelude::*;
 use std::uefi::guid;
 
@@ -33,3 +34,20 @@ pub fn dmi() -> Vec<dmi::Table> {
 
     vec![]
 }
+
+/// Get the system version, which is the model on System76 machines
+pub fn system_version() -> String {
+    for table in dmi() {
+        if table.header.kind == 1 {
+            if let Ok(info) = dmi::SystemInfo::from_bytes(&table.data) {
+                let index = info.version;
+                if index > 0 {
+                    if let Some(value) = table.strings.get((index - 1) as usize) {
+                        return value.trim().to_string();
+                    }
+                }
+            }
+        }
+    }
+    String::new()
+}